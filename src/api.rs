@@ -0,0 +1,396 @@
+//! High-level client API.
+//!
+//! An `MlsClient` acts for one user: it owns the user's state and a
+//! transport to the server, and saves the state after every operation.
+//!
+//! ```ignore
+//! let transport = Arc::new(HttpTransport::new(server, timeout));
+//! let alice = MlsClient::create("alice", settings, transport)?;
+//! alice.create_group("team")?;
+//! alice.add("team", "bob")?;
+//! alice.send("team", "hi")?;
+//! ```
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::context::Context;
+use crate::events::Subscriber;
+use crate::groups::{
+    add_to_group, check_new_user, create_group, do_update, init_user_keys,
+    join_group, persist, remove_from_group, send_message, OperationError,
+};
+use crate::polling::Polling;
+use crate::settings::Settings;
+use crate::state::{ReceivedMessage, State};
+use crate::storage::{load_state, set_passphrase};
+use crate::transport::{Transport, TransportError};
+
+/// What can go wrong in an `MlsClient` operation.
+#[derive(Debug)]
+pub enum Error {
+    /// The user isn't a member of the group.
+    UnknownGroup(String),
+    /// The user is a member of the group already.
+    AlreadyMember(String),
+    /// Reading or writing the state or the key files failed.
+    Storage(String),
+    /// Other members kept sending to the group at the same time, try
+    /// again later.
+    Conflict,
+    /// The user to add has no key package.
+    NoKeyPackage(String),
+    /// The server couldn't be reached or refused the request.
+    Transport(TransportError),
+    /// The operation failed for another reason.
+    Operation(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownGroup(group_id) => {
+                write!(f, "Not a member of {}", group_id)
+            }
+            Error::AlreadyMember(group_id) => {
+                write!(f, "Already a member of {}", group_id)
+            }
+            Error::Conflict => {
+                write!(f, "{}", OperationError::Conflict)
+            }
+            Error::NoKeyPackage(user_name) => {
+                write!(f, "{} has no key package", user_name)
+            }
+            Error::Transport(err) => write!(f, "{}", err),
+            Error::Storage(err) | Error::Operation(err) => {
+                write!(f, "{}", err)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<OperationError> for Error {
+    fn from(err: OperationError) -> Self {
+        match err {
+            OperationError::Conflict => Error::Conflict,
+            OperationError::NoKeyPackage(user_name) => {
+                Error::NoKeyPackage(user_name)
+            }
+            OperationError::Transport(err) => Error::Transport(err),
+            OperationError::Other(err) => Error::Operation(err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A user talking to an MLS server
+pub struct MlsClient {
+    ctx: Arc<Context>,
+    state: Arc<Mutex<State>>,
+    transport: Arc<dyn Transport>,
+}
+
+impl MlsClient {
+    /// Create a new user with a fresh identity, save their state, and
+    /// write and publish their keys. The settings are only used by this
+    /// client. Fails if there is a state file for the user already.
+    pub fn create(
+        name: &str,
+        settings: Settings,
+        transport: Arc<dyn Transport>,
+    ) -> Result<MlsClient> {
+        let ctx = context(settings)?;
+        check_new_user(&ctx, name).map_err(Error::Storage)?;
+        let state = State::new(name);
+        persist(&ctx, &state).map_err(Error::Storage)?;
+        init_user_keys(&ctx, &state, transport.as_ref())
+            .map_err(Error::Storage)?;
+        Ok(MlsClient::with_state(
+            ctx,
+            Arc::new(Mutex::new(state)),
+            transport,
+        ))
+    }
+
+    /// Load a user from `<name>.state` in the data directory. An
+    /// unencrypted file is only loaded if the passphrase is empty. The
+    /// state is saved in the current format, and the keys are written and
+    /// published again.
+    pub fn load(
+        name: &str,
+        passphrase: &str,
+        settings: Settings,
+        transport: Arc<dyn Transport>,
+    ) -> Result<MlsClient> {
+        let ctx = context(settings)?;
+        let state =
+            load_state(ctx.data_path(format!("{}.state", name)), || {
+                Ok(passphrase.to_string())
+            })
+            .map_err(Error::Storage)?;
+        persist(&ctx, &state).map_err(Error::Storage)?;
+        init_user_keys(&ctx, &state, transport.as_ref())
+            .map_err(Error::Storage)?;
+        Ok(MlsClient::with_state(
+            ctx,
+            Arc::new(Mutex::new(state)),
+            transport,
+        ))
+    }
+
+    /// Act for a user whose state and context are shared with others,
+    /// e.g. with the poller or with `Users`.
+    pub fn with_state(
+        ctx: Arc<Context>,
+        state: Arc<Mutex<State>>,
+        transport: Arc<dyn Transport>,
+    ) -> MlsClient {
+        MlsClient {
+            ctx,
+            state,
+            transport,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.state.lock().unwrap().name.clone()
+    }
+
+    /// The user's state, to share it with the poller.
+    pub fn state(&self) -> Arc<Mutex<State>> {
+        self.state.clone()
+    }
+
+    /// The client's settings and event subscribers, to share them with
+    /// the poller.
+    pub fn context(&self) -> Arc<Context> {
+        self.ctx.clone()
+    }
+
+    /// Pass the events seen by this client, e.g. received messages, to
+    /// `subscriber`.
+    pub fn subscribe(&self, subscriber: Subscriber) {
+        self.ctx.subscribe(subscriber);
+    }
+
+    /// Encrypt the state file with `passphrase` from now on, or store it
+    /// unencrypted if the passphrase is empty.
+    pub fn set_passphrase(&self, passphrase: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        set_passphrase(&mut state, passphrase).map_err(Error::Storage)?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// The groups the user is a member of.
+    pub fn groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> =
+            self.state.lock().unwrap().groups.keys().cloned().collect();
+        groups.sort();
+        groups
+    }
+
+    /// Create a group with the user as its only member.
+    pub fn create_group(&self, group_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_not_member(&state, group_id)?;
+        create_group(&mut state, group_id.into())?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// Add a user to a group and send them an invitation.
+    pub fn add(&self, group_id: &str, user_name: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_member(&state, group_id)?;
+        add_to_group(
+            &self.ctx,
+            &mut state,
+            self.transport.as_ref(),
+            group_id.into(),
+            user_name,
+        )?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// Join a group the user was invited to.
+    pub fn join(&self, group_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_not_member(&state, group_id)?;
+        join_group(
+            &self.ctx,
+            &mut state,
+            self.transport.as_ref(),
+            group_id.into(),
+        )?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// Replace the user's keys in a group.
+    pub fn update(&self, group_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_member(&state, group_id)?;
+        do_update(
+            &self.ctx,
+            &mut state,
+            self.transport.as_ref(),
+            group_id.into(),
+        )?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// Remove a user from a group.
+    pub fn remove(&self, group_id: &str, user_name: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_member(&state, group_id)?;
+        remove_from_group(
+            &self.ctx,
+            &mut state,
+            self.transport.as_ref(),
+            group_id.into(),
+            user_name.into(),
+        )?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// Send a text message to a group.
+    pub fn send(&self, group_id: &str, text: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_member(&state, group_id)?;
+        send_message(
+            &self.ctx,
+            &mut state,
+            self.transport.as_ref(),
+            group_id.into(),
+            text.into(),
+        )?;
+        persist(&self.ctx, &state).map_err(Error::Storage)
+    }
+
+    /// The names of the members of a group.
+    pub fn members(&self, group_id: &str) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let group_state = state
+            .groups
+            .get(group_id)
+            .ok_or_else(|| Error::UnknownGroup(group_id.into()))?;
+        Ok(group_state
+            .crypto
+            .get_members()
+            .iter()
+            .map(|cred| String::from_utf8_lossy(&cred.identity).into())
+            .collect())
+    }
+
+    /// The messages received in a group so far.
+    pub fn messages(&self, group_id: &str) -> Result<Vec<ReceivedMessage>> {
+        let state = self.state.lock().unwrap();
+        let group_state = state
+            .groups
+            .get(group_id)
+            .ok_or_else(|| Error::UnknownGroup(group_id.into()))?;
+        Ok(group_state.inbox.clone())
+    }
+
+    /// Fetch and process new blobs and invitations once, like a single
+    /// round of polling. Returns whether anything new arrived.
+    pub fn sync(&self) -> Result<bool> {
+        Polling::poll(
+            &self.ctx,
+            self.state.clone(),
+            self.transport.as_ref(),
+        )
+        .map_err(Error::Operation)
+    }
+
+    /// Save the state to `<user>.state` in the data directory.
+    pub fn save(&self) -> Result<()> {
+        persist(&self.ctx, &self.state.lock().unwrap())
+            .map_err(Error::Storage)
+    }
+}
+
+fn check_member(state: &State, group_id: &str) -> Result<()> {
+    if state.is_member(group_id) {
+        Ok(())
+    } else {
+        Err(Error::UnknownGroup(group_id.into()))
+    }
+}
+
+fn check_not_member(state: &State, group_id: &str) -> Result<()> {
+    if state.is_member(group_id) {
+        Err(Error::AlreadyMember(group_id.into()))
+    } else {
+        Ok(())
+    }
+}
+
+/// A context for a new client. Creates the data directory.
+fn context(settings: Settings) -> Result<Arc<Context>> {
+    fs::create_dir_all(&settings.data_dir).map_err(|e| {
+        Error::Storage(format!("Can't create {}: {}", settings.data_dir, e))
+    })?;
+    Ok(Arc::new(Context::new(settings)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, MlsClient};
+    use crate::testing::{memory_transport, texts, TestDir};
+
+    #[test]
+    fn create_add_join_update_remove() {
+        let dir = TestDir::new();
+        let transport = memory_transport();
+        let alice = dir.client("alice", &transport);
+        let bob = dir.client("bob", &transport);
+
+        alice.create_group("g").unwrap();
+        match alice.create_group("g") {
+            Err(Error::AlreadyMember(_)) => {}
+            _ => panic!("created the group twice"),
+        }
+        match alice.add("g", "carol") {
+            Err(Error::NoKeyPackage(name)) => assert_eq!(name, "carol"),
+            _ => panic!("added a user without a key package"),
+        }
+        alice.add("g", "bob").unwrap();
+        bob.join("g").unwrap();
+        assert_eq!(bob.groups(), vec!["g"]);
+        // Bob's add is processed with the next sync
+        bob.sync().unwrap();
+        assert_eq!(bob.members("g").unwrap(), vec!["alice", "bob"]);
+
+        alice.update("g").unwrap();
+        bob.sync().unwrap();
+        bob.update("g").unwrap();
+        alice.sync().unwrap();
+        bob.send("g", "after the updates").unwrap();
+        alice.sync().unwrap();
+        assert_eq!(texts(&alice, "g"), vec!["after the updates"]);
+
+        alice.remove("g", "bob").unwrap();
+        assert_eq!(alice.members("g").unwrap(), vec!["alice"]);
+        match alice.remove("h", "bob") {
+            Err(Error::UnknownGroup(_)) => {}
+            _ => panic!("removed from a group we're not in"),
+        }
+    }
+
+    #[test]
+    fn create_refuses_to_overwrite_a_user() {
+        let dir = TestDir::new();
+        let transport = memory_transport();
+        dir.client("alice", &transport);
+        let again =
+            MlsClient::create("alice", dir.settings(), transport.clone());
+        assert!(again.is_err());
+        let loaded =
+            MlsClient::load("alice", "", dir.settings(), transport);
+        assert_eq!(loaded.unwrap().name(), "alice");
+    }
+}
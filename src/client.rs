@@ -1,12 +1,23 @@
 //! Low-level logic for interacting with the server.
 
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
 use crate::message::Message;
+use crate::state::RetiredCredential;
+use crate::transport::{
+    PushEvent, Subscription, Transport, TransportError,
+};
+use crate::utils::{deserialize_codec, serialize_codec};
+use melissa::{keys, messages};
+use ring::digest;
 use serde::Serialize;
 use serde_json::json;
 
-use super::CLIENT;
-use super::SETTINGS;
-
 /// A blob, intended to be stored by the server. We can put any JSON we want
 /// into blobs.
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -15,48 +26,401 @@ pub struct Blob {
     pub content: Message,
 }
 
+impl Blob {
+    /// Hex-encoded SHA-256 of the blob's content.
+    pub fn digest(&self) -> String {
+        let content = serde_json::to_vec(&self.content).unwrap();
+        digest::digest(&digest::SHA256, &content)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Blobs {
     pub blobs: Vec<Blob>,
 }
 
-/// Store a blob for a specific group.
-pub fn append_blob(group_id: &str, blob: &Blob) -> reqwest::Result<()> {
-    let json = json!({
-        "index": blob.index,
-        "content": serde_json::to_string(&blob.content).unwrap()
-    });
-
-    println!(
-        "append_blob: {}/groups/{}/blobs, blob: {:?}",
-        SETTINGS.server, group_id, json
-    );
-    CLIENT
-        .post(
-            format!("{}/groups/{}/blobs", SETTINGS.server, group_id)
+/// A user's public key material. Users publish it on the server so that
+/// others can add them to groups.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyPackage {
+    #[serde(
+        serialize_with = "serialize_codec",
+        deserialize_with = "deserialize_codec"
+    )]
+    pub credential: keys::BasicCredential,
+    #[serde(
+        serialize_with = "serialize_codec",
+        deserialize_with = "deserialize_codec"
+    )]
+    pub init_key: keys::UserInitKey,
+}
+
+/// An invitation to a group, delivered through the invited user's mailbox
+/// on the server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Invitation {
+    /// Assigned by the server when the invitation is posted.
+    #[serde(default)]
+    pub id: u64,
+    pub group_id: String,
+    /// Name of the member who sent the invitation.
+    pub sender: String,
+    /// Index of the blob with the add operation.
+    pub index: i64,
+    /// Whether the recipient has already seen the invitation.
+    #[serde(default)]
+    pub acknowledged: bool,
+    #[serde(
+        serialize_with = "serialize_codec",
+        deserialize_with = "deserialize_codec"
+    )]
+    pub welcome: messages::Welcome,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Invitations {
+    welcomes: Vec<Invitation>,
+}
+
+/// Payload of a `blob` server-sent event
+#[derive(Deserialize)]
+struct BlobEvent {
+    group_id: String,
+    index: i64,
+}
+
+/// How long a push subscription waits for the server before the connection
+/// is closed.
+const SUBSCRIPTION_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Talks to a running MLS server over HTTP.
+pub struct HttpTransport {
+    client: reqwest::Client,
+    server: String,
+}
+
+impl HttpTransport {
+    pub fn new(server: String, timeout: Duration) -> HttpTransport {
+        HttpTransport {
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Can't create the HTTP client"),
+            server,
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    /// Store a blob for a specific group.
+    fn append_blob(
+        &self,
+        group_id: &str,
+        blob: &Blob,
+    ) -> Result<(), TransportError> {
+        let json = json!({
+            "index": blob.index,
+            "content": serde_json::to_string(&blob.content).unwrap()
+        });
+
+        debug!(
+            target: "transport",
+            "append_blob: {}/groups/{}/blobs, blob: {:?}",
+            self.server, group_id, json
+        );
+        let response = self
+            .client
+            .post(
+                format!("{}/groups/{}/blobs", self.server, group_id)
+                    .as_str(),
+            )
+            .json(&json)
+            .send()?;
+        // The server answers with 409 when the index is already taken
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            return Err(TransportError::Conflict);
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    /// Receive all blobs for a specific groups.
+    fn get_blobs(
+        &self,
+        group_id: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Blobs, TransportError> {
+        debug!(
+            target: "transport",
+            "get_blobs: {}/groups/{}/blobs",
+            self.server, group_id
+        );
+        let mut req = self.client.get(
+            format!("{}/groups/{}/blobs", self.server, group_id).as_str(),
+        );
+        if let Some(x) = from {
+            req = req.query(&[("from", x)])
+        };
+        if let Some(x) = to {
+            req = req.query(&[("to", x)])
+        };
+        Ok(req.send()?.json()?)
+    }
+
+    /// Store the user's key package, replacing the previous one.
+    fn publish_key_package(
+        &self,
+        user: &str,
+        package: &KeyPackage,
+    ) -> Result<(), TransportError> {
+        debug!(
+            target: "transport",
+            "publish_key_package: {}/users/{}",
+            self.server, user
+        );
+        self.client
+            .put(
+                format!("{}/users/{}/key_package", self.server, user)
+                    .as_str(),
+            )
+            .json(package)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Replace the user's list of retired credentials.
+    fn publish_retired_credentials(
+        &self,
+        user: &str,
+        credentials: &[RetiredCredential],
+    ) -> Result<(), TransportError> {
+        debug!(
+            target: "transport",
+            "publish_retired_credentials: {}/users/{}",
+            self.server, user
+        );
+        self.client
+            .put(
+                format!(
+                    "{}/users/{}/retired_credentials",
+                    self.server, user
+                )
+                .as_str(),
+            )
+            .json(credentials)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Fetch the user's retired credentials, if they have any.
+    fn get_retired_credentials(
+        &self,
+        user: &str,
+    ) -> Result<Vec<RetiredCredential>, TransportError> {
+        debug!(
+            target: "transport",
+            "get_retired_credentials: {}/users/{}",
+            self.server, user
+        );
+        let response = self
+            .client
+            .get(
+                format!(
+                    "{}/users/{}/retired_credentials",
+                    self.server, user
+                )
                 .as_str(),
-        )
-        .json(&json)
-        .send()?
-        .error_for_status()
-        .map(|_| ())
-}
-
-/// Receive all blobs for a specific groups.
-pub fn get_blobs(
-    group_id: &str,
-    from: Option<i64>,
-    to: Option<i64>,
-) -> reqwest::Result<Blobs> {
-    println!("get_blobs: {}/groups/{}/blobs", SETTINGS.server, group_id);
-    let mut req = CLIENT.get(
-        format!("{}/groups/{}/blobs", SETTINGS.server, group_id).as_str(),
-    );
-    if let Some(x) = from {
-        req = req.query(&[("from", x)])
-    };
-    if let Some(x) = to {
-        req = req.query(&[("to", x)])
-    };
-    req.send()?.json()
+            )
+            .send()?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        Ok(response.error_for_status()?.json()?)
+    }
+
+    /// Fetch a user's key package.
+    fn get_key_package(
+        &self,
+        user: &str,
+    ) -> Result<Option<KeyPackage>, TransportError> {
+        debug!(
+            target: "transport",
+            "get_key_package: {}/users/{}",
+            self.server, user
+        );
+        let response = self
+            .client
+            .get(
+                format!("{}/users/{}/key_package", self.server, user)
+                    .as_str(),
+            )
+            .send()?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json()?))
+    }
+
+    /// Put an invitation into the user's mailbox.
+    fn post_welcome(
+        &self,
+        user: &str,
+        invitation: &Invitation,
+    ) -> Result<(), TransportError> {
+        debug!(
+            target: "transport",
+            "post_welcome: {}/users/{}/welcomes",
+            self.server, user
+        );
+        self.client
+            .post(
+                format!("{}/users/{}/welcomes", self.server, user).as_str(),
+            )
+            .json(invitation)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Fetch all invitations from the user's mailbox.
+    fn get_welcomes(
+        &self,
+        user: &str,
+    ) -> Result<Vec<Invitation>, TransportError> {
+        debug!(
+            target: "transport",
+            "get_welcomes: {}/users/{}/welcomes",
+            self.server, user
+        );
+        let invitations: Invitations = self
+            .client
+            .get(
+                format!("{}/users/{}/welcomes", self.server, user).as_str(),
+            )
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(invitations.welcomes)
+    }
+
+    /// Mark an invitation as seen.
+    fn ack_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError> {
+        self.client
+            .post(
+                format!(
+                    "{}/users/{}/welcomes/{}/ack",
+                    self.server, user, id
+                )
+                .as_str(),
+            )
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Remove an invitation from the user's mailbox.
+    fn delete_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError> {
+        self.client
+            .delete(
+                format!("{}/users/{}/welcomes/{}", self.server, user, id)
+                    .as_str(),
+            )
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Open a server-sent events stream. The server sends `blob` events
+    /// with the group ID and index of new blobs, and `welcome` events when
+    /// an invitation arrives.
+    fn subscribe(
+        &self,
+        user: &str,
+        groups: &[String],
+        events: Sender<PushEvent>,
+    ) -> Result<Subscription, TransportError> {
+        debug!(
+            target: "transport",
+            "subscribe: {}/users/{}/events",
+            self.server, user
+        );
+        // Every read times out after a while, so that the thread below ends
+        // soon after the subscription is dropped, even when no events
+        // arrive. The poller then subscribes again and catches up.
+        let response = reqwest::Client::builder()
+            .timeout(SUBSCRIPTION_READ_TIMEOUT)
+            .build()?
+            .get(format!("{}/users/{}/events", self.server, user).as_str())
+            .query(&[("groups", groups.join(","))])
+            .header(reqwest::header::ACCEPT, "text/event-stream")
+            .send()?
+            .error_for_status()?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let subscription = Subscription::new(closed.clone());
+        let user = user.to_string();
+        thread::spawn(move || {
+            let mut event_type = String::new();
+            for line in BufReader::new(response).lines() {
+                if closed.load(Ordering::SeqCst) {
+                    return;
+                }
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if let Some(value) = line.strip_prefix("event:") {
+                    event_type = value.trim().into();
+                } else if let Some(data) = line.strip_prefix("data:") {
+                    let event = match event_type.as_str() {
+                        "blob" => match serde_json::from_str(data.trim()) {
+                            Ok(BlobEvent { group_id, index }) => {
+                                PushEvent::Blob {
+                                    user: user.clone(),
+                                    group_id,
+                                    index,
+                                }
+                            }
+                            Err(err) => {
+                                warn!(
+                                    target: "transport",
+                                    "Invalid blob event: {}",
+                                    err
+                                );
+                                continue;
+                            }
+                        },
+                        "welcome" => {
+                            PushEvent::Welcome { user: user.clone() }
+                        }
+                        _ => continue,
+                    };
+                    if events.send(event).is_err() {
+                        return;
+                    }
+                } else if line.is_empty() {
+                    event_type.clear();
+                }
+            }
+            // The connection was lost or has been idle for too long
+            if !closed.load(Ordering::SeqCst) {
+                let _ = events.send(PushEvent::Disconnected { user });
+            }
+        });
+        Ok(subscription)
+    }
 }
@@ -0,0 +1,55 @@
+//! What the library needs from the program around it.
+//!
+//! A `Context` holds the settings and the subscribers to group events.
+//! Every `MlsClient` has its own, so clients with different settings can
+//! live in one process; the REPL shares one between its users and the
+//! poller. Group operations, the poller and the simulation get the
+//! context passed in instead of reading globals.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::events::{Event, EventBus, Subscriber};
+use crate::settings::Settings;
+
+pub struct Context {
+    settings: RwLock<Settings>,
+    events: Mutex<EventBus>,
+}
+
+impl Context {
+    pub fn new(settings: Settings) -> Context {
+        Context {
+            settings: RwLock::new(settings),
+            events: Mutex::new(EventBus::new()),
+        }
+    }
+
+    pub fn settings(&self) -> RwLockReadGuard<'_, Settings> {
+        self.settings.read().unwrap()
+    }
+
+    /// Change the settings, e.g. from the REPL. The poller picks up the
+    /// change on its next round.
+    pub fn settings_mut(&self) -> RwLockWriteGuard<'_, Settings> {
+        self.settings.write().unwrap()
+    }
+
+    /// Path to a file in the data directory, where keys, welcome packages
+    /// and state files live.
+    pub fn data_path<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+        Path::new(&self.settings().data_dir).join(file)
+    }
+
+    /// Pass all further events to `subscriber`.
+    pub fn subscribe(&self, subscriber: Subscriber) {
+        self.events.lock().unwrap().subscribe(subscriber);
+    }
+
+    /// Send an event to all subscribers. The bus isn't locked while they
+    /// run.
+    pub fn emit(&self, event: Event) {
+        let events = self.events.lock().unwrap().clone();
+        events.emit(&event);
+    }
+}
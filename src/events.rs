@@ -0,0 +1,180 @@
+//! Events about what happens in groups.
+//!
+//! Whatever the client observes is emitted as an `Event` to all
+//! subscribers of the event bus in the `Context`. The console printer is
+//! one of them; others write events to a log file or, in the REPL, queue
+//! them for handlers in scripts.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// A blob was processed.
+    BlobReceived {
+        group_id: String,
+        index: i64,
+    },
+    MemberAdded {
+        group_id: String,
+        index: i64,
+        member: String,
+    },
+    MemberRemoved {
+        group_id: String,
+        index: i64,
+        member: String,
+    },
+    /// A handshake changed the keys without changing the roster.
+    KeyUpdated {
+        group_id: String,
+        index: i64,
+    },
+    /// An application message. The sender isn't verified, see
+    /// `ApplicationMessage`.
+    MessageReceived {
+        group_id: String,
+        index: i64,
+        claimed_sender: String,
+        text: String,
+    },
+    /// The server's history differs from what we have processed.
+    ForkDetected {
+        group_id: String,
+        index: i64,
+    },
+    TransportError {
+        error: String,
+    },
+    InvitationReceived {
+        user: String,
+        group_id: String,
+        sender: String,
+    },
+}
+
+impl Event {
+    /// The name used for the event in logs and in `on()`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::BlobReceived { .. } => "blob_received",
+            Event::MemberAdded { .. } => "member_added",
+            Event::MemberRemoved { .. } => "member_removed",
+            Event::KeyUpdated { .. } => "key_updated",
+            Event::MessageReceived { .. } => "message_received",
+            Event::ForkDetected { .. } => "fork_detected",
+            Event::TransportError { .. } => "transport_error",
+            Event::InvitationReceived { .. } => "invitation_received",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::BlobReceived { group_id, index } => {
+                write!(f, "{}: processed blob {}", group_id, index)
+            }
+            Event::MemberAdded {
+                group_id, member, ..
+            } => write!(f, "{}: {} was added", group_id, member),
+            Event::MemberRemoved {
+                group_id, member, ..
+            } => write!(f, "{}: {} was removed", group_id, member),
+            Event::KeyUpdated { group_id, index } => {
+                write!(f, "{}: keys updated at blob {}", group_id, index)
+            }
+            Event::MessageReceived {
+                group_id,
+                claimed_sender,
+                text,
+                ..
+            } => write!(
+                f,
+                "{}: <{} (unverified)> {}",
+                group_id, claimed_sender, text
+            ),
+            Event::ForkDetected { group_id, index } => write!(
+                f,
+                "{}: FORK DETECTED at blob {}: the server's history \
+                 differs from what we have processed",
+                group_id, index
+            ),
+            Event::TransportError { error } => {
+                write!(f, "Transport error: {}", error)
+            }
+            Event::InvitationReceived {
+                user,
+                group_id,
+                sender,
+            } => write!(
+                f,
+                "{} was invited to {} by {}",
+                user, group_id, sender
+            ),
+        }
+    }
+}
+
+/// Shared, so that the subscribers can be called without holding the lock
+/// on the bus; a subscriber may subscribe or emit events itself.
+pub type Subscriber = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, subscriber: Subscriber) {
+        self.subscribers.push(subscriber);
+    }
+
+    pub fn emit(&self, event: &Event) {
+        for subscriber in &self.subscribers {
+            subscriber(event);
+        }
+    }
+}
+
+/// Subscriber that prints events to the console. Processed blobs are left
+/// out: there is one for every blob, and what a blob changed is printed as
+/// its own event.
+pub fn print(event: &Event) {
+    if let Event::BlobReceived { .. } = event {
+        return;
+    }
+    println!("{}", event);
+}
+
+/// Subscriber that appends events to a file as JSON, one per line.
+pub fn log_file(path: &str) -> Result<Subscriber, String> {
+    let file: Mutex<File> = Mutex::new(
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?,
+    );
+    Ok(Arc::new(move |event: &Event| {
+        let line = serde_json::to_string(event).unwrap();
+        if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+            warn!(target: "repl", "Couldn't log the event: {}", err);
+        }
+    }))
+}
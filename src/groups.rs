@@ -0,0 +1,919 @@
+//! Group operations on a user's state.
+//!
+//! These are shared by `MlsClient`, the REPL, the poller and the
+//! simulation. Every operation works on one user's `State` and talks to
+//! the server through a `Transport`.
+
+use std::collections::hash_map;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use melissa::group;
+use melissa::keys;
+use melissa::messages;
+
+use crate::client::{Blob, Invitation, KeyPackage};
+use crate::context::Context;
+use crate::message::{ApplicationMessage, Message};
+use crate::polling::{process_message, sync_group};
+use crate::state::{GroupState, PendingRotation, State};
+use crate::storage::save_state;
+use crate::transport::{Transport, TransportError};
+use crate::utils::{read_codec, write_codec};
+
+/// Why a group operation failed.
+#[derive(Debug)]
+pub enum OperationError {
+    /// Other members kept taking the blob index we wanted, and we couldn't
+    /// catch up with them.
+    Conflict,
+    /// The user has no key package, neither on the server nor in the data
+    /// directory.
+    NoKeyPackage(String),
+    /// The server couldn't be reached or refused the request.
+    Transport(TransportError),
+    /// Any other failure.
+    Other(String),
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OperationError::Conflict => write!(
+                f,
+                "Couldn't send the message after {} attempts",
+                MAX_SEND_ATTEMPTS
+            ),
+            OperationError::NoKeyPackage(user) => {
+                write!(f, "Can't find a key package for {}", user)
+            }
+            OperationError::Transport(err) => write!(f, "{}", err),
+            OperationError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<String> for OperationError {
+    fn from(err: String) -> Self {
+        OperationError::Other(err)
+    }
+}
+
+impl From<TransportError> for OperationError {
+    fn from(err: TransportError) -> Self {
+        OperationError::Transport(err)
+    }
+}
+
+impl From<OperationError> for String {
+    fn from(err: OperationError) -> Self {
+        err.to_string()
+    }
+}
+
+/// Save the state to `<user>.state` after a change, so that changes are
+/// kept even when polling is disabled.
+pub fn persist(ctx: &Context, state: &State) -> Result<(), String> {
+    save_state(state, ctx.data_path(format!("{}.state", state.name)))
+}
+
+/// Make sure that creating a user called `name` doesn't overwrite the
+/// state of an existing one, which holds their keys and groups.
+pub fn check_new_user(ctx: &Context, name: &str) -> Result<(), String> {
+    let path = ctx.data_path(format!("{}.state", name));
+    if path.exists() {
+        Err(format!(
+            "{} exists already, load the user instead",
+            path.display()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Write a user's key files and publish their key package.
+pub fn init_user_keys(
+    ctx: &Context,
+    state: &State,
+    transport: &dyn Transport,
+) -> Result<(), String> {
+    write_key_files(ctx, state)?;
+    if ctx.settings().key_files {
+        info!(
+            target: "repl",
+            "Wrote {}.pub and {}.init",
+            state.name, state.name
+        );
+    }
+    // Other users can find the keys on the server as well; the files are a
+    // fallback for when the server doesn't store key packages
+    match publish_key_package(state, transport) {
+        Ok(()) => info!(target: "repl", "Published the key package"),
+        Err(err) => {
+            warn!(
+                target: "transport",
+                "Couldn't publish the key package: {}",
+                err
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Publish the user's credential and init key on the server.
+pub fn publish_key_package(
+    state: &State,
+    transport: &dyn Transport,
+) -> Result<(), String> {
+    transport
+        .publish_key_package(&state.name, &state.key_package())
+        .map_err(|e| e.to_string())
+}
+
+/// Write the user's credential to `<user>.pub`, the init key to
+/// `<user>.init`, and retired credentials to `<user>.retired`.
+/// Does nothing if `key_files` is off in the settings.
+pub fn write_key_files(ctx: &Context, state: &State) -> Result<(), String> {
+    if !ctx.settings().key_files {
+        return Ok(());
+    }
+    write_codec(
+        ctx.data_path(format!("{}.pub", state.name)),
+        &state.credential,
+    )
+    .map_err(|e| e.to_string())?;
+    if !state.retired_credentials.is_empty() {
+        let retired = serde_json::to_vec(&state.retired_credentials)
+            .map_err(|e| e.to_string())?;
+        fs::write(
+            ctx.data_path(format!("{}.retired", state.name)),
+            retired,
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    write_codec(
+        ctx.data_path(format!("{}.init", state.name)),
+        &state.init_key_bundle.init_key,
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Find a user's key package on the server. If the server doesn't have it,
+/// fall back to `<user>.pub` and `<user>.init` in the data directory.
+fn find_key_package(
+    ctx: &Context,
+    transport: &dyn Transport,
+    user_name: &str,
+) -> Result<KeyPackage, OperationError> {
+    match transport.get_key_package(user_name) {
+        Ok(Some(package)) => {
+            check_owner(&package.credential, user_name)?;
+            return Ok(package);
+        }
+        Ok(None) => {}
+        Err(err) => warn!(
+            target: "transport",
+            "Couldn't fetch the key package of {}: {}",
+            user_name, err
+        ),
+    }
+    let credential =
+        read_codec(ctx.data_path(format!("{}.pub", user_name)))
+            .map_err(|e| key_file_error(user_name, e))?;
+    check_owner(&credential, user_name)?;
+    let init_key = read_codec(ctx.data_path(format!("{}.init", user_name)))
+        .map_err(|e| key_file_error(user_name, e))?;
+    Ok(KeyPackage {
+        credential,
+        init_key,
+    })
+}
+
+/// Find a user's credential, either in their key package on the server or
+/// in `<user>.pub`.
+fn find_credential(
+    ctx: &Context,
+    transport: &dyn Transport,
+    user_name: &str,
+) -> Result<keys::BasicCredential, OperationError> {
+    let published = match transport.get_key_package(user_name) {
+        Ok(package) => package,
+        Err(err) => {
+            warn!(
+                target: "transport",
+                "Couldn't fetch the key package of {}: {}",
+                user_name, err
+            );
+            None
+        }
+    };
+    let credential = match published {
+        Some(package) => package.credential,
+        None => read_codec(ctx.data_path(format!("{}.pub", user_name)))
+            .map_err(|e| key_file_error(user_name, e))?,
+    };
+    check_owner(&credential, user_name)?;
+    Ok(credential)
+}
+
+/// A missing key file means that the user has no key package at all.
+fn key_file_error(user_name: &str, err: io::Error) -> OperationError {
+    if err.kind() == io::ErrorKind::NotFound {
+        OperationError::NoKeyPackage(user_name.into())
+    } else {
+        OperationError::Other(err.to_string())
+    }
+}
+
+/// Make sure that a credential we got for `user_name` is theirs. The server
+/// could hand out anybody's key package, and we would add the wrong person
+/// to the group.
+fn check_owner(
+    credential: &keys::BasicCredential,
+    user_name: &str,
+) -> Result<(), String> {
+    if credential.identity == user_name.as_bytes() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The key package for {} belongs to {}",
+            user_name,
+            String::from_utf8_lossy(&credential.identity)
+        ))
+    }
+}
+
+pub fn add_self_to_group(
+    ctx: &Context,
+    st: Arc<Mutex<State>>,
+    transport: &dyn Transport,
+    group_id: String,
+) -> Result<(), String> {
+    let mut state = st.lock().unwrap();
+    let name = state.name.clone();
+    add_to_group(ctx, &mut state, transport, group_id, &name)?;
+    Ok(())
+}
+
+pub fn add_to_group(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+    group_id: String,
+    user_name: &str,
+) -> Result<(), OperationError> {
+    debug!(target: "repl", "add to group {}, {}", group_id, user_name);
+    let sender = state.name.clone();
+    if let hash_map::Entry::Occupied(entry_group_state) =
+        state.groups.entry(group_id.clone())
+    {
+        let group_state = entry_group_state.into_mut();
+        // Read user info
+        let KeyPackage {
+            credential,
+            init_key,
+        } = find_key_package(ctx, transport, user_name)?;
+        // Generate a welcome package and send the add operation
+        let (welcome, index) = commit_operation(
+            ctx,
+            transport,
+            &group_id,
+            group_state,
+            |group_state| {
+                let index = group_state.next_blob;
+                let (welcome, add_raw) = group_state
+                    .crypto
+                    .create_add(credential.clone(), &init_key);
+                let add_op = messages::GroupOperation {
+                    msg_type: messages::GroupOperationType::Add,
+                    group_operation: messages::GroupOperationValue::Add(
+                        add_raw,
+                    ),
+                };
+                let handshake = group_state.crypto.create_handshake(add_op);
+                Ok((Message::Handshake(handshake), (welcome, index)))
+            },
+        )?;
+        // Deliver the welcome package to the user's mailbox
+        let invitation = Invitation {
+            id: 0,
+            group_id: group_id.clone(),
+            sender,
+            index,
+            acknowledged: false,
+            welcome,
+        };
+        match transport.post_welcome(user_name, &invitation) {
+            Ok(()) => {
+                info!(
+                    target: "repl",
+                    "Sent the invitation to {}",
+                    user_name
+                );
+                return Ok(());
+            }
+            Err(err) => warn!(
+                target: "transport",
+                "Couldn't send the invitation: {}",
+                err
+            ),
+        }
+        // If that didn't work, save the welcome package
+        write_codec(
+            ctx.data_path(format!("{}_{}.welcome", group_id, user_name)),
+            &invitation.welcome,
+        )
+        .map_err(|e| OperationError::Other(e.to_string()))?;
+        info!(
+            target: "repl",
+            "Wrote {}_{}.welcome",
+            group_id, user_name
+        );
+        Ok(())
+    } else {
+        Err(OperationError::Other("Group doesn't exist!".into()))
+    }
+}
+
+pub fn join_group(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+    group_id: String,
+) -> Result<(), OperationError> {
+    if state.is_member(&group_id) {
+        return Err(OperationError::Other(
+            "You're already a member of the group!".into(),
+        ));
+    }
+    // Look for an invitation in the mailbox first
+    let invitation = transport
+        .get_welcomes(&state.name)
+        .map_err(|err| {
+            warn!(
+                target: "transport",
+                "Couldn't check invitations: {}",
+                err
+            )
+        })
+        .ok()
+        .and_then(|invitations| {
+            invitations.into_iter().find(|i| i.group_id == group_id)
+        });
+    if let Some(invitation) = invitation {
+        return Ok(accept_invitation(state, transport, invitation)?);
+    }
+    // Otherwise import the group from the welcome file
+    let welcome: messages::Welcome = read_codec(
+        ctx.data_path(format!("{}_{}.welcome", group_id, state.name)),
+    )
+    .map_err(|e| OperationError::Other(e.to_string()))?;
+    let next_blob =
+        find_add_blob(transport, &group_id, welcome.transcript.len())?;
+    let group_crypto =
+        group::Group::new_from_welcome(state.identity.clone(), &welcome);
+    state
+        .groups
+        .insert(group_id, GroupState::new(group_crypto, next_blob));
+    Ok(())
+}
+
+/// Join a group using an invitation from the mailbox, and remove the
+/// invitation from the mailbox afterwards. The state of a group we have
+/// been removed from is replaced.
+pub fn accept_invitation(
+    state: &mut State,
+    transport: &dyn Transport,
+    invitation: Invitation,
+) -> Result<(), String> {
+    if state.is_member(&invitation.group_id) {
+        return Err("You're already a member of the group!".into());
+    }
+    let group_crypto = group::Group::new_from_welcome(
+        state.identity.clone(),
+        &invitation.welcome,
+    );
+    // The welcome package is the group as it was before the add, so we
+    // start at the add like `join_group` does
+    state.groups.insert(
+        invitation.group_id.clone(),
+        GroupState::new(group_crypto, invitation.index),
+    );
+    info!(
+        target: "repl",
+        "Joined {} (invited by {})",
+        invitation.group_id, invitation.sender
+    );
+    transport
+        .delete_welcome(&state.name, invitation.id)
+        .map_err(|e| e.to_string())
+}
+
+/// Decline all invitations to a group.
+pub fn decline_invitation(
+    state: &State,
+    transport: &dyn Transport,
+    group_id: String,
+) -> Result<(), String> {
+    let invitations = transport
+        .get_welcomes(&state.name)
+        .map_err(|e| e.to_string())?;
+    let mut found = false;
+    for invitation in invitations.iter().filter(|i| i.group_id == group_id)
+    {
+        transport
+            .delete_welcome(&state.name, invitation.id)
+            .map_err(|e| e.to_string())?;
+        found = true;
+    }
+    if found {
+        Ok(())
+    } else {
+        Err("No invitation to this group!".into())
+    }
+}
+
+/// Find the index of the blob with the add operation a welcome package was
+/// made for. A welcome package describes the group before the add: its
+/// transcript holds the handshakes up to there, and the joiner processes
+/// the add itself. Blobs can carry application messages as well, so the
+/// transcript length alone doesn't give us the blob index.
+fn find_add_blob(
+    transport: &dyn Transport,
+    group_id: &str,
+    transcript_len: usize,
+) -> Result<i64, String> {
+    let blobs = transport
+        .get_blobs(group_id, None, None)
+        .map_err(|e| e.to_string())?;
+    let mut handshakes = 0;
+    for blob in blobs.blobs {
+        if let Message::Handshake(_) = blob.content {
+            if handshakes == transcript_len {
+                return Ok(blob.index);
+            }
+            handshakes += 1;
+        }
+    }
+    Err("The server doesn't have the add for the welcome package".into())
+}
+
+pub fn do_update(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+    group_id: String,
+) -> Result<(), OperationError> {
+    if let hash_map::Entry::Occupied(entry_group_state) =
+        state.groups.entry(group_id.clone())
+    {
+        let group_state = entry_group_state.into_mut();
+        commit_operation(
+            ctx,
+            transport,
+            &group_id,
+            group_state,
+            |group_state| {
+                let update_op = messages::GroupOperation {
+                    msg_type: messages::GroupOperationType::Update,
+                    group_operation: messages::GroupOperationValue::Update(
+                        group_state.crypto.create_update(),
+                    ),
+                };
+                let handshake =
+                    group_state.crypto.create_handshake(update_op);
+                Ok((Message::Handshake(handshake), ()))
+            },
+        )?;
+        group_state.mark_updated();
+        Ok(())
+    } else {
+        Err(OperationError::Other("Group doesn't exist!".into()))
+    }
+}
+
+pub fn remove_from_group(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+    group_id: String,
+    user_name: String,
+) -> Result<(), OperationError> {
+    if let hash_map::Entry::Occupied(entry_group_state) =
+        state.groups.entry(group_id.clone())
+    {
+        let group_state = entry_group_state.into_mut();
+        // Find the user; we can't find them by username because we don't
+        // get usernames from add operations, so we have to look at the key
+        let credential = find_credential(ctx, transport, &user_name)?;
+        commit_operation(
+            ctx,
+            transport,
+            &group_id,
+            group_state,
+            |group_state| {
+                // The roster might change between attempts, so the slot has
+                // to be looked up every time
+                let slot = group_state
+                    .crypto
+                    .get_members()
+                    .iter()
+                    .position(|k| k.public_key == credential.public_key)
+                    .ok_or_else(|| "User not found!".to_string())?;
+                // Create a remove operation
+                let remove_raw = group_state.crypto.create_remove(slot);
+                let remove_op = messages::GroupOperation {
+                    msg_type: messages::GroupOperationType::Remove,
+                    group_operation: messages::GroupOperationValue::Remove(
+                        remove_raw,
+                    ),
+                };
+                let handshake =
+                    group_state.crypto.create_handshake(remove_op);
+                Ok((Message::Handshake(handshake), ()))
+            },
+        )
+    } else {
+        Err(OperationError::Other("Group doesn't exist!".into()))
+    }
+}
+
+pub fn send_message(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+    group_id: String,
+    text: String,
+) -> Result<(), OperationError> {
+    let name = state.name.clone();
+    if let Some(group_state) = state.groups.get_mut(&group_id) {
+        commit_operation(
+            ctx,
+            transport,
+            &group_id,
+            group_state,
+            |group_state| {
+                let message = ApplicationMessage::seal(
+                    &group_state.crypto,
+                    &name,
+                    &text,
+                )?;
+                Ok((Message::Application(message), ()))
+            },
+        )
+    } else {
+        Err(OperationError::Other("Group doesn't exist!".into()))
+    }
+}
+
+/// How many times we try to get a message accepted by the server before
+/// giving up.
+const MAX_SEND_ATTEMPTS: usize = 5;
+
+/// Create a message with `make`, apply it locally and send it to the
+/// server. If somebody else has already taken the blob index, the group
+/// state is rolled back to what it was before the message was created,
+/// the missing blobs are fetched and processed, and the message is created
+/// again for the new group state. Nothing is sent in a group with a fork.
+fn commit_operation<T, F>(
+    ctx: &Context,
+    transport: &dyn Transport,
+    group_id: &str,
+    group_state: &mut GroupState,
+    mut make: F,
+) -> Result<T, OperationError>
+where
+    F: FnMut(&mut GroupState) -> Result<(Message, T), String>,
+{
+    if let Some(index) = group_state.forks.iter().next() {
+        return Err(OperationError::Other(format!(
+            "{} has a fork at blob {}, not sending anything",
+            group_id, index
+        )));
+    }
+    for _ in 0..MAX_SEND_ATTEMPTS {
+        let backup = group_state.clone();
+        let (content, result) = make(group_state)?;
+        let blob = Blob {
+            index: group_state.next_blob,
+            content,
+        };
+        process_message(ctx, group_id, group_state, blob.clone());
+        match transport.append_blob(group_id, &blob) {
+            Ok(()) => return Ok(result),
+            Err(TransportError::Conflict) => {
+                info!(
+                    target: "repl",
+                    "{}: blob {} was taken by someone else, retrying",
+                    group_id, blob.index
+                );
+                *group_state = backup;
+                sync_group(ctx, transport, group_id, group_state)?;
+            }
+            Err(err) => {
+                *group_state = backup;
+                return Err(err.into());
+            }
+        }
+    }
+    Err(OperationError::Conflict)
+}
+
+/// Replace the user's identity. In every group, the new identity is added
+/// and then removes the old one, since group members can't change their
+/// credential with an update. The new identity is saved before the first
+/// group is changed; if a group fails, the old identity stays in use and
+/// calling this again finishes the rotation with the same new identity.
+/// Only when every group has been moved is the old credential retired and
+/// published as such, and the new keys are written and published.
+pub fn rotate_identity(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+) -> Result<(), String> {
+    let identity = match &state.rotation {
+        Some(rotation) => rotation.identity.clone(),
+        None => {
+            let identity = keys::Identity::random();
+            state.rotation = Some(PendingRotation {
+                identity: identity.clone(),
+            });
+            persist(ctx, state)?;
+            identity
+        }
+    };
+    let credential = State::credential_for(&state.name, &identity);
+    let old_credential = state.credential.clone();
+    let mut group_ids: Vec<String> = state.groups.keys().cloned().collect();
+    group_ids.sort();
+    let mut failed = Vec::new();
+    for group_id in group_ids {
+        let group_state = state.groups.get_mut(&group_id).unwrap();
+        let result = rotate_in_group(
+            ctx,
+            transport,
+            &group_id,
+            group_state,
+            &identity,
+            &credential,
+            &old_credential,
+        );
+        if let Err(err) = result {
+            warn!(
+                target: "crypto",
+                "{}: couldn't rotate the identity: {}",
+                group_id, err
+            );
+            failed.push(group_id);
+        }
+        // Keep what was committed, even if the next group fails
+        persist(ctx, state)?;
+    }
+    if !failed.is_empty() {
+        return Err(format!(
+            "The old identity is still used in {}; call rotate_identity() \
+             again to finish",
+            failed.join(", ")
+        ));
+    }
+    state.rotation = None;
+    state.replace_identity(identity);
+    persist(ctx, state)?;
+    write_key_files(ctx, state)?;
+    if let Err(err) = publish_key_package(state, transport) {
+        warn!(
+            target: "transport",
+            "Couldn't publish the key package: {}",
+            err
+        );
+    }
+    let retired = transport.publish_retired_credentials(
+        &state.name,
+        &state.retired_credentials,
+    );
+    if let Err(err) = retired {
+        warn!(
+            target: "transport",
+            "Couldn't publish the retired credentials: {}",
+            err
+        );
+    }
+    persist(ctx, state)
+}
+
+/// Move one group to the new identity. Each step is skipped if the roster
+/// shows it's done already, so a group that failed halfway is finished by
+/// the next attempt.
+fn rotate_in_group(
+    ctx: &Context,
+    transport: &dyn Transport,
+    group_id: &str,
+    group_state: &mut GroupState,
+    identity: &keys::Identity,
+    credential: &keys::BasicCredential,
+    old_credential: &keys::BasicCredential,
+) -> Result<(), String> {
+    let is_member = |group_state: &GroupState,
+                     key: &keys::BasicCredential| {
+        group_state
+            .crypto
+            .get_members()
+            .iter()
+            .any(|k| k.public_key == key.public_key)
+    };
+    if !is_member(group_state, credential) {
+        // Add the new identity with a one-off init key
+        let init_key = keys::UserInitKeyBundle::new(identity).init_key;
+        let (welcome, add) = commit_operation(
+            ctx,
+            transport,
+            group_id,
+            group_state,
+            |group_state| {
+                let (welcome, add_raw) = group_state
+                    .crypto
+                    .create_add(credential.clone(), &init_key);
+                let add_op = messages::GroupOperation {
+                    msg_type: messages::GroupOperationType::Add,
+                    group_operation: messages::GroupOperationValue::Add(
+                        add_raw,
+                    ),
+                };
+                let handshake = group_state.crypto.create_handshake(add_op);
+                Ok((
+                    Message::Handshake(handshake.clone()),
+                    (welcome, handshake),
+                ))
+            },
+        )?;
+        // Continue as the new identity. Like any joiner, it starts from the
+        // group before the add and processes the add itself; `next_blob`
+        // is past the add already.
+        group_state.crypto =
+            group::Group::new_from_welcome(identity.clone(), &welcome);
+        group_state.crypto.process_handshake(add);
+    }
+    if !is_member(group_state, old_credential) {
+        return Ok(());
+    }
+    // Remove the old identity
+    commit_operation(
+        ctx,
+        transport,
+        group_id,
+        group_state,
+        |group_state| {
+            let slot = group_state
+                .crypto
+                .get_members()
+                .iter()
+                .position(|k| k.public_key == old_credential.public_key)
+                .ok_or_else(|| {
+                    "The old identity is not a member!".to_string()
+                })?;
+            let remove_op = messages::GroupOperation {
+                msg_type: messages::GroupOperationType::Remove,
+                group_operation: messages::GroupOperationValue::Remove(
+                    group_state.crypto.create_remove(slot),
+                ),
+            };
+            let handshake = group_state.crypto.create_handshake(remove_op);
+            Ok((Message::Handshake(handshake), ()))
+        },
+    )?;
+    Ok(())
+}
+
+pub fn create_group(
+    state: &mut State,
+    group_id: String,
+) -> Result<(), OperationError> {
+    let identity = state.identity.clone();
+    let credential = state.credential.clone();
+    match state.groups.entry(group_id) {
+        hash_map::Entry::Occupied(_) => {
+            Err(OperationError::Other("Group already exists!".into()))
+        }
+        hash_map::Entry::Vacant(slot) => {
+            let group_crypto = group::Group::new(
+                identity,
+                credential,
+                group::GroupId::random(),
+            );
+            slot.insert(GroupState::new(group_crypto, 0));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::polling::check_invitations;
+    use crate::testing::{texts, two_members_in_a_group};
+    use crate::utils::write_codec;
+
+    #[test]
+    fn join_through_the_mailbox_and_the_welcome_file() {
+        // Bob joins with the invitation in his mailbox
+        let members = two_members_in_a_group();
+        let (alice, bob) = (&members.alice, &members.bob);
+        let transport = &members.transport;
+        alice.send("g", "hi bob").unwrap();
+        bob.sync().unwrap();
+        assert_eq!(texts(bob, "g"), vec!["hi bob"]);
+
+        // Carol joins with a welcome file, after an application message
+        let carol = members.dir.client("carol", transport);
+        alice.add("g", "carol").unwrap();
+        let invitation = transport.get_welcomes("carol").unwrap().remove(0);
+        transport.delete_welcome("carol", invitation.id).unwrap();
+        let path = members.dir.path.join("g_carol.welcome");
+        write_codec(path, &invitation.welcome).unwrap();
+        carol.join("g").unwrap();
+        carol.send("g", "hi all").unwrap();
+        alice.sync().unwrap();
+        bob.sync().unwrap();
+        assert_eq!(texts(alice, "g"), vec!["hi bob", "hi all"]);
+        assert_eq!(texts(bob, "g"), vec!["hi bob", "hi all"]);
+        assert_eq!(texts(&carol, "g"), vec!["hi all"]);
+        let roster = alice.members("g").unwrap();
+        assert_eq!(roster, vec!["alice", "bob", "carol"]);
+        assert_eq!(bob.members("g").unwrap(), roster);
+        assert_eq!(carol.members("g").unwrap(), roster);
+    }
+
+    #[test]
+    fn retry_after_a_conflict() {
+        let members = two_members_in_a_group();
+        let (alice, bob) = (&members.alice, &members.bob);
+        // Bob hasn't seen Alice's message, so his index is taken
+        alice.send("g", "one").unwrap();
+        bob.send("g", "two").unwrap();
+        assert_eq!(texts(bob, "g"), vec!["one", "two"]);
+        alice.sync().unwrap();
+        assert_eq!(texts(alice, "g"), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn join_again_after_being_removed() {
+        let members = two_members_in_a_group();
+        let (alice, bob) = (&members.alice, &members.bob);
+        let transport = &members.transport;
+        alice.remove("g", "bob").unwrap();
+        bob.sync().unwrap();
+        assert_eq!(alice.members("g").unwrap(), vec!["alice"]);
+        assert!(!bob.state().lock().unwrap().is_member("g"));
+        assert!(bob.send("g", "still here?").is_err());
+
+        // The new invitation isn't taken for one to a group we are in
+        alice.add("g", "bob").unwrap();
+        check_invitations(
+            &bob.context(),
+            &mut bob.state().lock().unwrap(),
+            transport.as_ref(),
+        )
+        .unwrap();
+        assert_eq!(transport.get_welcomes("bob").unwrap().len(), 1);
+        bob.join("g").unwrap();
+        bob.sync().unwrap();
+        alice.send("g", "welcome back").unwrap();
+        bob.sync().unwrap();
+        assert_eq!(texts(bob, "g"), vec!["welcome back"]);
+        assert_eq!(bob.members("g").unwrap(), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn rotate_identity_in_all_groups() {
+        let members = two_members_in_a_group();
+        let (alice, bob) = (&members.alice, &members.bob);
+        let transport = &members.transport;
+        let state = alice.state();
+        let old_key = state.lock().unwrap().credential.public_key;
+        super::rotate_identity(
+            &alice.context(),
+            &mut state.lock().unwrap(),
+            transport.as_ref(),
+        )
+        .unwrap();
+        {
+            let state = state.lock().unwrap();
+            assert!(state.rotation.is_none());
+            assert!(state.credential.public_key != old_key);
+            assert_eq!(state.retired_credentials.len(), 1);
+        }
+        let retired = transport.get_retired_credentials("alice").unwrap();
+        assert_eq!(retired.len(), 1);
+        assert!(retired[0].credential.public_key == old_key);
+        let package = transport.get_key_package("alice").unwrap().unwrap();
+        assert!(package.credential.public_key != old_key);
+
+        bob.sync().unwrap();
+        assert_eq!(alice.members("g").unwrap(), vec!["bob", "alice"]);
+        assert_eq!(bob.members("g").unwrap(), vec!["bob", "alice"]);
+        alice.send("g", "new key").unwrap();
+        bob.sync().unwrap();
+        assert_eq!(texts(bob, "g"), vec!["new key"]);
+    }
+}
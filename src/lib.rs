@@ -0,0 +1,48 @@
+//! A prototype MLS client.
+//!
+//! `MlsClient` is the entry point for embedding the client: it creates or
+//! loads a user and runs group operations on their behalf. The REPL in
+//! `main.rs` is built on top of it. The modules below are public as well,
+//! for callers that need more control, like the poller or a custom
+//! `Transport`. The library keeps no global state: settings and event
+//! subscribers live in a `Context`, and the program decides where log
+//! output goes by installing a logger, e.g. `logging::Logger`.
+
+pub mod api;
+pub mod client;
+pub mod context;
+pub mod events;
+pub mod groups;
+pub mod logging;
+pub mod message;
+pub mod polling;
+pub mod push;
+pub mod random;
+pub mod settings;
+#[cfg(test)]
+mod simulation;
+pub mod state;
+pub mod storage;
+#[cfg(test)]
+mod testing;
+pub mod transport;
+pub mod users;
+pub mod utils;
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate log;
+
+extern crate atty;
+extern crate config;
+extern crate melissa;
+extern crate names;
+extern crate rand;
+extern crate reqwest;
+extern crate ring;
+extern crate rpassword;
+extern crate serde;
+
+pub use crate::api::{Error, MlsClient};
+pub use crate::context::Context;
@@ -0,0 +1,167 @@
+//! Diagnostic output.
+//!
+//! Diagnostics go through the `log` macros with one of the targets in
+//! `TARGETS`, e.g. `debug!(target: "transport", ...)`. Every target has its
+//! own level (`log_level` and `log_levels` in `Settings.toml`, or
+//! `log_level()` in the REPL). Messages from other crates are only shown
+//! from `warn` up. Besides the console, records can be written to a file as
+//! JSON, one per line, to compare what several clients did after a run.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+use crate::settings::Settings;
+
+/// The parts of the client that can be logged separately.
+pub const TARGETS: &[&str] = &["transport", "polling", "crypto", "repl"];
+
+pub struct Logger {
+    config: RwLock<Config>,
+}
+
+struct Config {
+    /// Level for our targets that don't have their own.
+    default: LevelFilter,
+    targets: HashMap<String, LevelFilter>,
+    file: Option<Mutex<File>>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger::new()
+    }
+}
+
+impl Logger {
+    pub fn new() -> Logger {
+        Logger {
+            config: RwLock::new(Config {
+                default: LevelFilter::Info,
+                targets: HashMap::new(),
+                file: None,
+            }),
+        }
+    }
+
+    /// Take the levels and the log file from the settings.
+    pub fn configure(&self, settings: &Settings) -> Result<(), String> {
+        self.set_level(None, &settings.log_level)?;
+        for (target, level) in &settings.log_levels {
+            self.set_level(Some(target), level)?;
+        }
+        if let Some(path) = &settings.log_file {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Can't open {}: {}", path, e))?;
+            self.config.write().unwrap().file = Some(Mutex::new(file));
+        }
+        Ok(())
+    }
+
+    /// Set the level of a target, or the default level.
+    pub fn set_level(
+        &self,
+        target: Option<&str>,
+        level: &str,
+    ) -> Result<(), String> {
+        let level = LevelFilter::from_str(level)
+            .map_err(|_| format!("Unknown log level {}", level))?;
+        let mut config = self.config.write().unwrap();
+        match target {
+            None => config.default = level,
+            Some(target) if TARGETS.contains(&target) => {
+                config.targets.insert(target.into(), level);
+            }
+            Some(target) => {
+                return Err(format!(
+                    "Unknown log target {}, expected one of {}",
+                    target,
+                    TARGETS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// The effective level of every target.
+    pub fn levels(&self) -> Vec<String> {
+        let config = self.config.read().unwrap();
+        let mut levels = vec![format!("default: {}", config.default)];
+        for target in TARGETS {
+            levels.push(format!("{}: {}", target, config.level(target)));
+        }
+        levels
+    }
+}
+
+impl Config {
+    fn level(&self, target: &str) -> LevelFilter {
+        if let Some(level) = self.targets.get(target) {
+            *level
+        } else if TARGETS.contains(&target) {
+            self.default
+        } else {
+            LevelFilter::Warn
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level()
+            <= self.config.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Informational messages are meant for the user, so they are
+        // printed as they are
+        if record.level() == Level::Info {
+            println!("{}", record.args());
+        } else {
+            println!(
+                "[{} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+        if let Some(file) = &self.config.read().unwrap().file {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            let line = json!({
+                "time": time,
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.config.read().unwrap().file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Install the logger for the process. Levels are filtered by the logger
+/// itself, so everything is passed on to it.
+pub fn init(logger: &'static Logger) {
+    log::set_logger(logger).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+}
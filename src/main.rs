@@ -1,72 +1,236 @@
-pub mod client;
-pub mod message;
-pub mod polling;
-pub mod repl;
-pub mod settings;
-pub mod state;
-pub mod utils;
+mod repl;
 
 #[macro_use]
-extern crate serde_derive;
+extern crate log;
 
-extern crate config;
+extern crate atty;
+extern crate clap;
 extern crate lazy_static;
-extern crate melissa;
-extern crate names;
-extern crate reqwest;
+extern crate mls_client;
 extern crate rhai;
 extern crate rustyline;
 extern crate serde;
 
+use std::fs;
+use std::io::{self, Read};
+use std::process::exit;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use settings::Settings;
-use state::State;
+use clap::{App, Arg};
 
-use crate::polling::Polling;
-use crate::repl::REPLDictionary;
+use mls_client::client::HttpTransport;
+use mls_client::logging::Logger;
+use mls_client::polling::Polling;
+use mls_client::random::Random;
+use mls_client::settings::Settings;
+use mls_client::state::State;
+use mls_client::transport::Transport;
+use mls_client::users::Users;
+use mls_client::{events, groups, logging, random, storage, Context};
+
+use crate::repl::{REPLDictionary, ScriptHandlers};
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref SETTINGS: Settings = Settings::new().unwrap();
-    pub static ref CLIENT: reqwest::Client = reqwest::Client::new();
+    pub static ref RANDOM: Mutex<Random> = Mutex::new(Random::new());
+    pub static ref LOGGER: Logger = Logger::new();
     pub static ref POLLING: Mutex<Polling> = Mutex::new(Polling::new());
+    pub static ref HANDLERS: Mutex<ScriptHandlers> =
+        Mutex::new(ScriptHandlers::new());
     pub static ref REPL: Mutex<REPLDictionary> =
         Mutex::new(REPLDictionary::new());
+    pub static ref FAILURE: Mutex<Option<String>> = Mutex::new(None);
 }
 
 fn main() {
+    let args = App::new("mls-client")
+        .about("A prototype MLS client")
+        .arg(
+            Arg::with_name("script")
+                .long("script")
+                .value_name("FILE")
+                .help("Run a script and exit instead of starting the REPL"),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .help(
+                    "Create a user with this name instead of a random one",
+                )
+                .conflicts_with("state"),
+        )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .value_name("USER")
+                .help("Load the user's state from <USER>.state"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Apply the settings in Settings.<PROFILE>.toml"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .value_name("URL")
+                .help("Override the server from Settings.toml"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed the user names picked by the client"),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help(
+                    "Directory for keys, welcome packages and state files",
+                ),
+        )
+        .get_matches();
+
     // Read settings
-    println!("{:?}", SETTINGS.server);
+    let ctx = {
+        let mut settings = Settings::new(args.value_of("profile"))
+            .unwrap_or_else(|e| {
+                eprintln!("Can't read the settings: {}", e);
+                exit(2)
+            });
+        if let Some(server) = args.value_of("server") {
+            settings.server = server.into();
+        }
+        if let Some(data_dir) = args.value_of("data-dir") {
+            settings.data_dir = data_dir.into();
+        }
+        if let Some(seed) = args.value_of("seed") {
+            settings.seed = Some(seed.parse().unwrap_or_else(|e| {
+                eprintln!("Invalid seed {}: {}", seed, e);
+                exit(2)
+            }));
+        }
+        logging::init(&LOGGER);
+        LOGGER.configure(&settings).unwrap_or_else(|e| {
+            eprintln!("Can't set up logging: {}", e);
+            exit(2)
+        });
+        println!("{:?}", settings.server);
+        fs::create_dir_all(&settings.data_dir).unwrap_or_else(|e| {
+            eprintln!("Can't create {}: {}", settings.data_dir, e);
+            exit(2)
+        });
+        Arc::new(Context::new(settings))
+    };
+
+    // Seed client-side randomness. Scripted runs always get a seed, so that
+    // a run can pick the same user names again with `--seed`
+    let scripted =
+        args.is_present("script") || !atty::is(atty::Stream::Stdin);
+    let seed = match ctx.settings().seed {
+        Some(seed) => Some(seed),
+        None if scripted => Some(random::new_seed()),
+        None => None,
+    };
+    if let Some(seed) = seed {
+        println!("Seed: {}", seed);
+        RANDOM.lock().unwrap().seed(seed);
+    }
+
+    // Print events, and queue them for handlers in scripts
+    ctx.subscribe(Arc::new(events::print));
+    ctx.subscribe(Arc::new(repl::queue_for_scripts));
+
+    let transport: Arc<dyn Transport> = {
+        let settings = ctx.settings();
+        Arc::new(HttpTransport::new(
+            settings.server.clone(),
+            Duration::from_secs(settings.request_timeout_secs),
+        ))
+    };
 
     // Local state
-    let name = names::Generator::default().next().unwrap();
-    let state: Arc<Mutex<State>> =
-        Arc::new(Mutex::new(State::new(name.as_str())));
-    println!("\nCreated new user '{}'", name);
+    let state = match args.value_of("state") {
+        Some(user_name) => {
+            let path = ctx.data_path(format!("{}.state", user_name));
+            let state = storage::load_state(&path, || {
+                storage::read_passphrase("Passphrase: ")
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Can't load {}: {}", path.display(), e);
+                exit(2)
+            });
+            println!("\nLoaded user '{}'", state.name);
+            state
+        }
+        None => {
+            let name = match args.value_of("name") {
+                Some(name) => name.into(),
+                None => RANDOM.lock().unwrap().name(),
+            };
+            groups::check_new_user(&ctx, &name).unwrap_or_else(|e| {
+                eprintln!("Can't create {}: {}", name, e);
+                exit(2)
+            });
+            println!("\nCreated new user '{}'", name);
+            let mut state = State::new(name.as_str());
+            storage::read_passphrase(
+                "Passphrase for the state file (empty for none): ",
+            )
+            .and_then(|passphrase| {
+                storage::set_passphrase(&mut state, &passphrase)
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Can't protect the state: {}", e);
+                exit(2)
+            });
+            state
+        }
+    };
+    groups::persist(&ctx, &state).unwrap();
 
     // Write user's keys
-    {
-        let state = state.lock().unwrap();
-        utils::write_codec(
-            format!("{}.pub", state.name),
-            &state.credential,
-        )
-        .unwrap();
-        utils::write_codec(
-            format!("{}.init", state.name),
-            &state.init_key_bundle.init_key,
-        )
-        .unwrap();
-        println!("Wrote {}.pub and {}.init", state.name, state.name);
-    }
+    groups::init_user_keys(&ctx, &state, transport.as_ref()).unwrap();
+    let users = Arc::new(Mutex::new(Users::new(state)));
 
     // REPL instances
     let mut engine = rhai::Engine::new();
     // Prepare the REPL
     repl::register_types(&mut engine);
-    repl::register_functions(state, &mut engine);
+    repl::register_functions(
+        ctx.clone(),
+        users.clone(),
+        transport,
+        &mut engine,
+    );
 
-    // Start the REPL
-    repl::start(&mut engine);
+    // Run a script if we were given one, either as a file or on stdin;
+    // otherwise start the REPL
+    let script = match args.value_of("script") {
+        Some(path) => Some(fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Can't read {}: {}", path, e);
+            exit(2)
+        })),
+        None if !atty::is(atty::Stream::Stdin) => {
+            let mut source = String::new();
+            io::stdin().read_to_string(&mut source).unwrap();
+            Some(source)
+        }
+        None => None,
+    };
+    match script {
+        Some(source) => {
+            let succeeded =
+                repl::run_script(&mut engine, users.clone(), &source);
+            repl::shutdown(&ctx, &users);
+            if !succeeded {
+                exit(1)
+            }
+        }
+        None => repl::start(&mut engine, &ctx, users),
+    }
 }
@@ -1,23 +1,119 @@
-use melissa::messages;
+use melissa::{group, messages};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{aead, hkdf};
 use std::fmt;
 
 use crate::utils::{deserialize_codec, serialize_codec};
 
 /// Any kind of message stored by the server.
 #[derive(Clone, Serialize, Deserialize)]
-pub struct Message(
-    #[serde(
-        serialize_with = "serialize_codec",
-        deserialize_with = "deserialize_codec"
-    )]
-    pub messages::Handshake,
-);
+pub enum Message {
+    /// A group operation (add, update, remove).
+    Handshake(
+        #[serde(
+            serialize_with = "serialize_codec",
+            deserialize_with = "deserialize_codec"
+        )]
+        messages::Handshake,
+    ),
+    /// Content encrypted for the current members of the group.
+    Application(ApplicationMessage),
+}
 
 impl fmt::Debug for Message {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let Message(handshake) = self;
-        fmt.debug_tuple("Handshake")
-            .field(&handshake.operation.msg_type)
-            .finish()
+        match self {
+            Message::Handshake(handshake) => fmt
+                .debug_tuple("Handshake")
+                .field(&handshake.operation.msg_type)
+                .finish(),
+            Message::Application(message) => fmt
+                .debug_tuple("Application")
+                .field(&message.claimed_sender)
+                .finish(),
+        }
     }
 }
+
+/// An application (chat) message. The payload is encrypted with a key
+/// derived from the group's application secret, so it can only be read by
+/// members who are in the same epoch as the sender was.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApplicationMessage {
+    /// Name of the sender, as claimed by whoever sent the message. Also
+    /// used as associated data. The message isn't signed, so any member
+    /// can claim to be any other member; `open` only checks that the name
+    /// belongs to a current member.
+    #[serde(alias = "sender")]
+    pub claimed_sender: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl ApplicationMessage {
+    /// Encrypt a text message for the group.
+    pub fn seal(
+        crypto: &group::Group,
+        sender: &str,
+        text: &str,
+    ) -> Result<ApplicationMessage, String> {
+        let key = application_key(crypto)?;
+        // Nonces always come from the system RNG, even with `--seed`: a
+        // replayed session would reuse them with the same key
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "Failed to generate a nonce".to_string())?;
+        let mut ciphertext = text.as_bytes().to_vec();
+        key.seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(sender.as_bytes()),
+            &mut ciphertext,
+        )
+        .map_err(|_| "Failed to encrypt the message".to_string())?;
+        Ok(ApplicationMessage {
+            claimed_sender: sender.into(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt the message using the group's current key. Messages that
+    /// claim to come from someone who isn't in the roster are rejected;
+    /// the claimed sender isn't verified otherwise.
+    pub fn open(&self, crypto: &group::Group) -> Result<String, String> {
+        let is_member = crypto
+            .get_members()
+            .iter()
+            .any(|cred| cred.identity == self.claimed_sender.as_bytes());
+        if !is_member {
+            return Err(format!("{} is not a member", self.claimed_sender));
+        }
+        let key = application_key(crypto)?;
+        let nonce = aead::Nonce::try_assume_unique_for_key(&self.nonce)
+            .map_err(|_| "Malformed nonce".to_string())?;
+        let mut buffer = self.ciphertext.clone();
+        let plaintext = key
+            .open_in_place(
+                nonce,
+                aead::Aad::from(self.claimed_sender.as_bytes()),
+                &mut buffer,
+            )
+            .map_err(|_| "Failed to decrypt the message".to_string())?;
+        String::from_utf8(plaintext.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+/// Derive the AEAD key for application messages in the group's current
+/// epoch.
+fn application_key(
+    crypto: &group::Group,
+) -> Result<aead::LessSafeKey, String> {
+    let secret = crypto.get_application_secret();
+    let prk =
+        hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(secret.as_ref());
+    let okm = prk
+        .expand(&[b"mls-client application"], &aead::AES_256_GCM)
+        .map_err(|_| "Failed to derive the message key".to_string())?;
+    Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+}
@@ -1,36 +1,158 @@
 extern crate reqwest;
 extern crate serde_json;
 
-use std::fs::File;
+use melissa::keys;
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 
-use crate::client::{get_blobs, Blob};
-use crate::state::{GroupState, State};
-use std::sync::mpsc::{channel, Sender, TryRecvError};
+use rand::Rng;
+
+use crate::client::Blob;
+use crate::context::Context;
+use crate::events::Event;
+use crate::groups::{accept_invitation, do_update, persist};
+use crate::message::Message;
+use crate::push::Push;
+use crate::state::{Checkpoint, GroupState, ReceivedMessage, State};
+use crate::transport::{Transport, TransportError};
+use crate::users::Users;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 
 pub struct Polling {
-    handle: Option<Sender<()>>,
+    handle: Option<PollThread>,
+    status: Arc<Mutex<PollStatus>>,
+}
+
+/// The running polling thread
+struct PollThread {
+    /// Dropping or sending to it tells the thread to stop.
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+/// What the polling thread is up to, for `poll_status()`
+#[derive(Default)]
+pub struct PollStatus {
+    /// The last error, with the time it happened.
+    pub last_error: Option<(SystemTime, String)>,
+    /// Errors in a row since the last successful poll.
+    pub failures: u32,
+    pub interval: Duration,
+    pub next_poll: Option<SystemTime>,
+}
+
+impl PollStatus {
+    /// Pick the time until the next poll. Polling speeds up to the minimum
+    /// interval when something arrives and slows down to the normal
+    /// interval when nothing happens. After errors it backs off
+    /// exponentially, with jitter so that clients don't retry in lockstep.
+    fn schedule(
+        &mut self,
+        ctx: &Context,
+        result: Result<bool, String>,
+    ) -> Duration {
+        let settings = ctx.settings().clone();
+        let min = Duration::from_millis(settings.min_poll_interval_ms);
+        let normal = Duration::from_millis(settings.poll_interval_ms);
+        let max = Duration::from_millis(settings.max_poll_interval_ms);
+        self.interval = match result {
+            Ok(true) => {
+                self.failures = 0;
+                min
+            }
+            Ok(false) => {
+                self.failures = 0;
+                (self.interval * 2).max(min).min(normal)
+            }
+            Err(err) => {
+                ctx.emit(Event::TransportError { error: err.clone() });
+                self.last_error = Some((SystemTime::now(), err));
+                self.failures += 1;
+                let backoff = normal
+                    .checked_mul(2u32.saturating_pow(self.failures))
+                    .map_or(max, |backoff| backoff.min(max))
+                    .as_millis() as u64;
+                // Not from the seeded RNG: the poller runs on its own
+                // clock, so drawing from it would break replaying a seed
+                let jitter =
+                    rand::thread_rng().gen_range(0, backoff / 2 + 1);
+                Duration::from_millis(backoff / 2 + jitter)
+            }
+        };
+        self.next_poll = Some(SystemTime::now() + self.interval);
+        self.interval
+    }
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Polling::new()
+    }
 }
 
 impl Polling {
     pub fn new() -> Polling {
         Polling {
             handle: Option::None,
+            status: Arc::new(Mutex::new(PollStatus::default())),
         }
     }
 
-    pub fn start_polling(&mut self, state: Arc<Mutex<State>>) {
+    /// A description of the polling state: the interval, when the next poll
+    /// is due and the last error.
+    pub fn status(&self) -> String {
+        if !self.is_polling() {
+            return "Not polling".into();
+        }
+        let status = self.status.lock().unwrap();
+        let mut lines = vec![format!("Interval: {:?}", status.interval)];
+        if let Some(next_poll) = status.next_poll {
+            let wait = next_poll
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            lines.push(format!("Next poll in {:?}", wait));
+        }
+        match &status.last_error {
+            Some((time, err)) => {
+                let ago = time.elapsed().unwrap_or_default();
+                lines.push(format!(
+                    "Last error {:?} ago ({} in a row): {}",
+                    ago, status.failures, err
+                ));
+            }
+            None => lines.push("No errors".into()),
+        }
+        lines.join("\n")
+    }
+
+    pub fn start_polling(
+        &mut self,
+        ctx: Arc<Context>,
+        users: Arc<Mutex<Users>>,
+        transport: Arc<dyn Transport>,
+    ) {
         if self.handle.is_some() {
             self.stop_polling();
         }
-        self.handle = Option::Some(Polling::spawn(state));
+        self.handle = Option::Some(Polling::spawn(
+            ctx,
+            users,
+            transport,
+            self.status.clone(),
+        ));
     }
 
+    /// Stop polling and wait until the thread has finished what it was
+    /// doing, so that no state is written after this returns.
     pub fn stop_polling(&mut self) {
-        if let Some(h) = self.handle.take() {
-            drop(h);
+        if let Some(handle) = self.handle.take() {
+            // The thread might have stopped already if it panicked
+            let _ = handle.stop.send(());
+            if handle.thread.join().is_err() {
+                error!(target: "polling", "The polling thread has crashed");
+            }
         }
     }
 
@@ -38,57 +160,601 @@ impl Polling {
         self.handle.is_some()
     }
 
-    fn spawn(state: Arc<Mutex<State>>) -> Sender<()> {
-        let (sender, receiver) = channel();
-        thread::spawn(move || loop {
-            match receiver.try_recv() {
-                Err(TryRecvError::Disconnected) => break,
-                _ => {
-                    Polling::poll(state.clone());
-                    thread::sleep(Duration::from_secs(1));
+    fn spawn(
+        ctx: Arc<Context>,
+        users: Arc<Mutex<Users>>,
+        transport: Arc<dyn Transport>,
+        status: Arc<Mutex<PollStatus>>,
+    ) -> PollThread {
+        let (stop, receiver) = channel();
+        let thread = thread::spawn(move || {
+            let mut push = Push::new();
+            loop {
+                if stop_requested(&receiver) {
+                    break;
+                }
+                // Don't hold the lock on the users while polling, so that
+                // the REPL can switch users in the meantime
+                let states = users.lock().unwrap().all();
+                let push_enabled = ctx.settings().push;
+                if push_enabled {
+                    push.subscribe(&states, transport.as_ref());
+                } else {
+                    push.unsubscribe();
+                }
+                // Users with a push subscription get synced when the server
+                // tells us about new blobs, and polled only now and then
+                let mut result = Ok(false);
+                for state in &states {
+                    let name = state.lock().unwrap().name.clone();
+                    let polled = if push.needs_poll(&ctx, &name) {
+                        let polled = Polling::poll(
+                            &ctx,
+                            state.clone(),
+                            transport.as_ref(),
+                        );
+                        if polled.is_ok() {
+                            push.polled(&name);
+                        }
+                        polled
+                    } else {
+                        Polling::update(
+                            &ctx,
+                            state.clone(),
+                            transport.as_ref(),
+                        )
+                    };
+                    match polled {
+                        Ok(activity) => {
+                            result = result.map(|seen| seen || activity)
+                        }
+                        Err(err) => result = Err(err),
+                    }
+                }
+                let interval =
+                    status.lock().unwrap().schedule(&ctx, result);
+                if push.wait(
+                    &ctx,
+                    &states,
+                    transport.as_ref(),
+                    interval,
+                    &receiver,
+                ) {
+                    break;
                 }
             }
+            status.lock().unwrap().next_poll = None;
         });
 
-        sender
+        PollThread { stop, thread }
     }
 
-    /// Poll for messages in subscribed groups and perform scheduled updates.
-    /// Also save state to disk.
-    fn poll(state: Arc<Mutex<State>>) {
+    /// Poll for messages in subscribed groups and perform scheduled
+    /// updates. Also save state to disk. Returns whether anything new
+    /// arrived; a group that can't be synced doesn't stop the others from
+    /// syncing.
+    pub fn poll(
+        ctx: &Context,
+        state: Arc<Mutex<State>>,
+        transport: &dyn Transport,
+    ) -> Result<bool, String> {
         let mut state = state.lock().unwrap();
+        let before = progress(&state);
+        let mut errors = Vec::new();
         // Download blobs
         for (group_id, group_state) in state.groups.iter_mut() {
-            let blobs =
-                get_blobs(group_id, Some(group_state.next_blob), None)
-                    .unwrap();
-            for blob in blobs.blobs {
-                process_message(&group_id, group_state, blob)
+            if let Err(err) =
+                sync_group(ctx, transport, group_id, group_state)
+            {
+                errors.push(format!("{}: {}", group_id, err));
             }
         }
+        // Check for invitations
+        if let Err(err) = check_invitations(ctx, &mut state, transport) {
+            errors.push(err);
+        }
+        // Update keys where the update policy asks for it
+        if let Err(err) = scheduled_updates(ctx, &mut state, transport) {
+            errors.push(err);
+        }
         // Save state to disk
-        let file = File::create(format!("{}.state", state.name)).unwrap();
-        serde_json::to_writer(file, &*state).unwrap();
+        if let Err(err) = persist(ctx, &state) {
+            errors.push(format!("Couldn't save the state: {}", err));
+        }
+        if errors.is_empty() {
+            Ok(progress(&state) != before)
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// Do the scheduled updates for a user whose blobs are pushed, and
+    /// save the state if anything was done.
+    fn update(
+        ctx: &Context,
+        state: Arc<Mutex<State>>,
+        transport: &dyn Transport,
+    ) -> Result<bool, String> {
+        let mut state = state.lock().unwrap();
+        let updated = scheduled_updates(ctx, &mut state, transport)?;
+        if updated {
+            persist(ctx, &state)?;
+        }
+        Ok(updated)
+    }
+}
+
+/// Do an update in every group whose update policy says it's time. Returns
+/// whether any updates were done.
+pub fn scheduled_updates(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+) -> Result<bool, String> {
+    let mut due: Vec<String> = state
+        .groups
+        .iter()
+        .filter(|(_, group_state)| group_state.update_due())
+        .map(|(group_id, _)| group_id.clone())
+        .collect();
+    due.sort();
+    let mut errors = Vec::new();
+    for group_id in &due {
+        info!(target: "polling", "{}: doing a scheduled update", group_id);
+        if let Err(err) = do_update(ctx, state, transport, group_id.clone())
+        {
+            errors.push(format!(
+                "{}: scheduled update failed: {}",
+                group_id, err
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(!due.is_empty())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+/// The number of processed blobs and joined groups, to tell whether a poll
+/// brought anything new.
+fn progress(state: &State) -> (i64, usize) {
+    let blobs = state.groups.values().map(|g| g.next_blob).sum();
+    (blobs, state.groups.len())
+}
+
+/// Whether the REPL wants the polling thread to stop.
+pub fn stop_requested(receiver: &Receiver<()>) -> bool {
+    match receiver.try_recv() {
+        Ok(()) | Err(TryRecvError::Disconnected) => true,
+        Err(TryRecvError::Empty) => false,
+    }
+}
+
+/// Announce new invitations from the mailbox and, if the settings allow
+/// it, join the groups right away. Invitations to groups we are in
+/// already are deleted. Errors are collected, so that one bad invitation
+/// doesn't keep us from handling the others.
+pub fn check_invitations(
+    ctx: &Context,
+    state: &mut State,
+    transport: &dyn Transport,
+) -> Result<(), String> {
+    let invitations = transport
+        .get_welcomes(&state.name)
+        .map_err(|err| format!("Couldn't check invitations: {}", err))?;
+    let settings = ctx.settings().clone();
+    let mut errors = Vec::new();
+    for invitation in invitations {
+        if !invitation.acknowledged {
+            ctx.emit(Event::InvitationReceived {
+                user: state.name.clone(),
+                group_id: invitation.group_id.clone(),
+                sender: invitation.sender.clone(),
+            });
+            if let Err(err) =
+                transport.ack_welcome(&state.name, invitation.id)
+            {
+                errors.push(format!(
+                    "Couldn't acknowledge the invitation to {}: {}",
+                    invitation.group_id, err
+                ));
+            }
+        }
+        let group_id = invitation.group_id.clone();
+        if state.is_member(&group_id) {
+            // We can't join a group twice, so the invitation would stay in
+            // the mailbox forever
+            info!(
+                target: "polling",
+                "Already a member of {}, dropping the invitation",
+                group_id
+            );
+            if let Err(err) =
+                transport.delete_welcome(&state.name, invitation.id)
+            {
+                errors.push(format!(
+                    "Couldn't delete the invitation to {}: {}",
+                    group_id, err
+                ));
+            }
+        } else if settings.joins_automatically(&invitation.sender) {
+            if let Err(err) =
+                accept_invitation(state, transport, invitation)
+            {
+                errors.push(format!("Couldn't join {}: {}", group_id, err));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
     }
 }
 
+/// Download and process all blobs we haven't seen yet.
+pub fn sync_group(
+    ctx: &Context,
+    transport: &dyn Transport,
+    group_id: &str,
+    group_state: &mut GroupState,
+) -> Result<(), TransportError> {
+    if !group_state.forks.is_empty() {
+        return Ok(());
+    }
+    let blobs =
+        transport.get_blobs(group_id, Some(group_state.next_blob), None)?;
+    for blob in blobs.blobs {
+        process_message(ctx, group_id, group_state, blob)
+    }
+    Ok(())
+}
+
 /// Process a single message.
 pub fn process_message(
+    ctx: &Context,
     group_id: &str,
     group_state: &mut GroupState,
     message: Blob,
 ) {
-    println!("{}: got {:?}", group_id, message);
-    // TODO: we skip blobs that are older than what we've seen, but we don't check that they correspond to what we've seen.
+    debug!(target: "polling", "{}: got {:?}", group_id, message);
+    if !group_state.forks.is_empty() {
+        debug!(
+            target: "polling",
+            "{}: ignoring blob {}, the group has a fork",
+            group_id, message.index
+        );
+        return;
+    }
     match message.index {
         ix if ix == group_state.next_blob => {
-            group_state.crypto.process_handshake(message.content.0);
-            group_state.next_blob += 1;
-        }
-        ix if ix > group_state.next_blob => println!(
-            "Blob from the future: expected index {}, got {}",
-            group_state.next_blob, message.index
-        ),
-        _ => {}
+            apply_blob(ctx, group_id, group_state, message);
+            // The blobs we have buffered might be next in line now
+            while let Some(blob) =
+                group_state.pending.remove(&group_state.next_blob)
+            {
+                apply_blob(ctx, group_id, group_state, blob);
+            }
+            let next_blob = group_state.next_blob;
+            group_state.pending = group_state.pending.split_off(&next_blob);
+        }
+        ix if ix > group_state.next_blob => {
+            if let Some(buffered) = group_state.pending.get(&ix) {
+                // Two different blobs with the same index means that the
+                // server shows different histories; keep the first one
+                if buffered.digest() != message.digest() {
+                    report_fork(ctx, group_id, group_state, ix)
+                }
+            } else if group_state.pending.len()
+                < ctx.settings().max_buffered_blobs
+            {
+                debug!(
+                    target: "polling",
+                    "{}: buffering blob {} from the future, expected {}",
+                    group_id, ix, group_state.next_blob
+                );
+                group_state.pending.insert(ix, message);
+            } else {
+                warn!(
+                    target: "polling",
+                    "Blob from the future: expected index {}, got {}",
+                    group_state.next_blob,
+                    ix
+                )
+            }
+        }
+        ix => {
+            // We have seen this blob already, but it should still be the
+            // same blob
+            if !check_blob(group_state, &message) {
+                report_fork(ctx, group_id, group_state, ix)
+            }
+        }
+    }
+}
+
+/// Process the blob that comes right after the last processed one.
+fn apply_blob(
+    ctx: &Context,
+    group_id: &str,
+    group_state: &mut GroupState,
+    message: Blob,
+) {
+    let ix = message.index;
+    group_state.record_digest(ix, message.digest());
+    match message.content {
+        Message::Handshake(handshake) => {
+            // Handshakes don't tell what they did in a way we can inspect,
+            // so compare the rosters before and after
+            let before = group_state.crypto.get_members();
+            debug!(
+                target: "crypto",
+                "{}: processing handshake {}",
+                group_id, ix
+            );
+            group_state.crypto.process_handshake(handshake);
+            let after = group_state.crypto.get_members();
+            let name = |cred: &keys::BasicCredential| -> String {
+                String::from_utf8_lossy(&cred.identity).into()
+            };
+            let mut changed = false;
+            for cred in &after {
+                if !before.iter().any(|c| c.public_key == cred.public_key) {
+                    changed = true;
+                    ctx.emit(Event::MemberAdded {
+                        group_id: group_id.into(),
+                        index: ix,
+                        member: name(cred),
+                    });
+                }
+            }
+            for cred in &before {
+                if !after.iter().any(|c| c.public_key == cred.public_key) {
+                    changed = true;
+                    ctx.emit(Event::MemberRemoved {
+                        group_id: group_id.into(),
+                        index: ix,
+                        member: name(cred),
+                    });
+                }
+            }
+            if !changed {
+                ctx.emit(Event::KeyUpdated {
+                    group_id: group_id.into(),
+                    index: ix,
+                });
+            }
+        }
+        Message::Application(app) => match app.open(&group_state.crypto) {
+            Ok(text) => {
+                ctx.emit(Event::MessageReceived {
+                    group_id: group_id.into(),
+                    index: ix,
+                    claimed_sender: app.claimed_sender.clone(),
+                    text: text.clone(),
+                });
+                group_state.inbox.push(ReceivedMessage {
+                    index: ix,
+                    claimed_sender: app.claimed_sender,
+                    text,
+                })
+            }
+            Err(err) => warn!(
+                target: "crypto",
+                "{}: can't read message from {}: {}",
+                group_id, app.claimed_sender, err
+            ),
+        },
+    }
+    group_state.next_blob += 1;
+    group_state.blobs_since_update += 1;
+    ctx.emit(Event::BlobReceived {
+        group_id: group_id.into(),
+        index: ix,
+    });
+}
+
+/// Check that a blob we have already processed has the same content as
+/// back then. Returns `true` if we don't know the blob's digest.
+pub fn check_blob(group_state: &GroupState, blob: &Blob) -> bool {
+    match group_state.digests.get(&blob.index) {
+        Some(digest) => *digest == blob.digest(),
+        None => true,
+    }
+}
+
+fn report_fork(
+    ctx: &Context,
+    group_id: &str,
+    group_state: &mut GroupState,
+    index: i64,
+) {
+    ctx.emit(Event::ForkDetected {
+        group_id: group_id.into(),
+        index,
+    });
+    group_state.forks.insert(index);
+}
+
+/// Download the part of the history that we have already processed and
+/// check it against the digests we recorded. Returns the indices of the
+/// blobs that don't match; a mismatch in the blobs folded into the
+/// checkpoint is reported at the checkpoint's first blob.
+pub fn verify_history(
+    ctx: &Context,
+    transport: &dyn Transport,
+    group_id: &str,
+    group_state: &mut GroupState,
+) -> Result<Vec<i64>, TransportError> {
+    let from = match (
+        &group_state.checkpoint,
+        group_state.digests.keys().next(),
+    ) {
+        (Some(checkpoint), _) => checkpoint.from,
+        (None, Some(from)) => *from,
+        (None, None) => return Ok(Vec::new()),
+    };
+    let blobs = transport.get_blobs(
+        group_id,
+        Some(from),
+        Some(group_state.next_blob),
+    )?;
+    let received: BTreeMap<i64, Blob> = blobs
+        .blobs
+        .into_iter()
+        .map(|blob| (blob.index, blob))
+        .collect();
+    let mut mismatches = Vec::new();
+    if let Some(checkpoint) = &group_state.checkpoint {
+        let mut chain = Checkpoint::new(checkpoint.from);
+        for index in checkpoint.from..checkpoint.to {
+            match received.get(&index) {
+                Some(blob) => chain.extend(&blob.digest()),
+                None => break,
+            }
+        }
+        if chain != *checkpoint {
+            mismatches.push(checkpoint.from);
+        }
+    }
+    for index in group_state.digests.keys() {
+        // A blob that has disappeared counts as a fork as well
+        let matches = received
+            .get(index)
+            .is_some_and(|blob| check_blob(group_state, blob));
+        if !matches {
+            mismatches.push(*index);
+        }
+    }
+    for index in &mismatches {
+        report_fork(ctx, group_id, group_state, *index);
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{process_message, verify_history};
+    use crate::events::Event;
+    use crate::state::{Checkpoint, MAX_DIGESTS};
+    use crate::testing::{texts, two_members_in_a_group};
+
+    #[test]
+    fn detect_a_fork_and_freeze_the_group() {
+        let members = two_members_in_a_group();
+        let (alice, bob) = (&members.alice, &members.bob);
+        for text in &["one", "two", "three", "four"] {
+            alice.send("g", text).unwrap();
+        }
+        let forks = Arc::new(Mutex::new(Vec::new()));
+        let seen = forks.clone();
+        bob.subscribe(Arc::new(move |event| {
+            if let Event::ForkDetected { index, .. } = event {
+                seen.lock().unwrap().push(*index);
+            }
+        }));
+
+        let ctx = bob.context();
+        let state = bob.state();
+        let mut state = state.lock().unwrap();
+        let group_state = state.groups.get_mut("g").unwrap();
+        let first = group_state.next_blob;
+        let transport = &members.transport;
+        let blobs = transport.get_blobs("g", Some(first), None).unwrap();
+        let blobs = blobs.blobs;
+
+        // A buffered blob comes back with different content
+        let mut buffered = group_state.clone();
+        process_message(&ctx, "g", &mut buffered, blobs[3].clone());
+        let mut other = blobs[2].clone();
+        other.index = blobs[3].index;
+        process_message(&ctx, "g", &mut buffered, other);
+        assert!(buffered.forks.contains(&blobs[3].index));
+
+        // So does a processed one
+        process_message(&ctx, "g", group_state, blobs[0].clone());
+        let mut other = blobs[1].clone();
+        other.index = blobs[0].index;
+        process_message(&ctx, "g", group_state, other);
+        assert!(group_state.forks.contains(&blobs[0].index));
+        assert_eq!(
+            *forks.lock().unwrap(),
+            vec![blobs[3].index, blobs[0].index]
+        );
+
+        // Nothing more is processed in the group
+        process_message(&ctx, "g", group_state, blobs[1].clone());
+        assert_eq!(group_state.next_blob, first + 1);
+        drop(state);
+        assert!(bob.send("g", "after the fork").is_err());
+    }
+
+    #[test]
+    fn buffer_blobs_that_arrive_out_of_order() {
+        let members = two_members_in_a_group();
+        for text in &["one", "two", "three"] {
+            members.alice.send("g", text).unwrap();
+        }
+        let ctx = members.bob.context();
+        let state = members.bob.state();
+        let mut state = state.lock().unwrap();
+        let group_state = state.groups.get_mut("g").unwrap();
+        let blobs = members
+            .transport
+            .get_blobs("g", Some(group_state.next_blob), None)
+            .unwrap()
+            .blobs;
+        process_message(&ctx, "g", group_state, blobs[2].clone());
+        process_message(&ctx, "g", group_state, blobs[1].clone());
+        assert_eq!(group_state.pending.len(), 2);
+        process_message(&ctx, "g", group_state, blobs[0].clone());
+        assert!(group_state.pending.is_empty());
+        drop(state);
+        assert_eq!(texts(&members.bob, "g"), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn fold_old_digests_into_the_checkpoint() {
+        let members = two_members_in_a_group();
+        members.alice.send("g", "one").unwrap();
+        let ctx = members.alice.context();
+        let state = members.alice.state();
+        let mut state = state.lock().unwrap();
+        let group_state = state.groups.get_mut("g").unwrap();
+        let transport = members.transport.as_ref();
+        assert!(verify_history(&ctx, transport, "g", group_state)
+            .unwrap()
+            .is_empty());
+
+        // Pretend that many more blobs were processed
+        let mut many = group_state.clone();
+        for index in 0..(MAX_DIGESTS as i64 + 10) {
+            many.record_digest(index, index.to_string());
+        }
+        assert_eq!(many.digests.len(), MAX_DIGESTS);
+        let checkpoint = many.checkpoint.unwrap();
+        assert_eq!((checkpoint.from, checkpoint.to), (0, 10));
+
+        // The real history still checks out when folded into a checkpoint
+        let mut folded = group_state.clone();
+        let mut checkpoint: Option<Checkpoint> = None;
+        for (index, digest) in std::mem::take(&mut folded.digests) {
+            checkpoint
+                .get_or_insert_with(|| Checkpoint::new(index))
+                .extend(&digest);
+        }
+        let from = checkpoint.as_ref().unwrap().from;
+        folded.checkpoint = checkpoint;
+        assert!(verify_history(&ctx, transport, "g", &mut folded)
+            .unwrap()
+            .is_empty());
+        folded.checkpoint.as_mut().unwrap().hash = "tampered".into();
+        assert_eq!(
+            verify_history(&ctx, transport, "g", &mut folded).unwrap(),
+            vec![from]
+        );
     }
 }
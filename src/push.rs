@@ -0,0 +1,225 @@
+//! Push-based delivery.
+//!
+//! Instead of asking the server for new blobs every second, the poller can
+//! keep a subscription per user open and sync a group only when the server
+//! says that something was stored there. Users whose subscription can't be
+//! opened, or whose connection is lost, are polled as before. Subscribed
+//! users are still polled right after subscribing, to catch up with what
+//! was stored before, and every `max_poll_interval_ms` in case an event got
+//! lost.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::context::Context;
+use crate::events::Event;
+use crate::groups::persist;
+use crate::polling::{check_invitations, stop_requested, sync_group};
+use crate::state::State;
+use crate::transport::{PushEvent, Subscription, Transport};
+
+/// How often `Push::wait` checks whether it should stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Push subscriptions of all local users
+pub struct Push {
+    sender: Sender<PushEvent>,
+    receiver: Receiver<PushEvent>,
+    /// Open subscriptions by user name
+    subscriptions: HashMap<String, Subscribed>,
+    /// Users we couldn't subscribe for, so that we complain only once.
+    failed: HashSet<String>,
+}
+
+/// An open subscription of one user
+struct Subscribed {
+    /// The groups the subscription covers.
+    groups: Vec<String>,
+    /// Only kept so that the subscription stays open.
+    _subscription: Subscription,
+    /// When the user was last polled, if at all since subscribing.
+    last_poll: Option<Instant>,
+}
+
+impl Default for Push {
+    fn default() -> Self {
+        Push::new()
+    }
+}
+
+impl Push {
+    pub fn new() -> Push {
+        let (sender, receiver) = channel();
+        Push {
+            sender,
+            receiver,
+            subscriptions: HashMap::new(),
+            failed: HashSet::new(),
+        }
+    }
+
+    /// Make sure every user has a subscription for all of their groups.
+    pub fn subscribe(
+        &mut self,
+        states: &[Arc<Mutex<State>>],
+        transport: &dyn Transport,
+    ) {
+        for state in states {
+            let (name, groups) = {
+                let state = state.lock().unwrap();
+                let mut groups: Vec<String> =
+                    state.groups.keys().cloned().collect();
+                groups.sort();
+                (state.name.clone(), groups)
+            };
+            if let Some(subscribed) = self.subscriptions.get(&name) {
+                if subscribed.groups == groups {
+                    continue;
+                }
+            }
+            match transport.subscribe(&name, &groups, self.sender.clone()) {
+                Ok(subscription) => {
+                    self.failed.remove(&name);
+                    self.subscriptions.insert(
+                        name,
+                        Subscribed {
+                            groups,
+                            _subscription: subscription,
+                            last_poll: None,
+                        },
+                    );
+                }
+                Err(err) => {
+                    self.subscriptions.remove(&name);
+                    if self.failed.insert(name.clone()) {
+                        warn!(
+                            target: "polling",
+                            "Couldn't subscribe for {}, polling instead: {}",
+                            name, err
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Close all subscriptions.
+    pub fn unsubscribe(&mut self) {
+        self.subscriptions.clear();
+        self.failed.clear();
+    }
+
+    /// Whether a user should be polled instead of waiting for events: if
+    /// they have no subscription, if they haven't been polled since
+    /// subscribing, or if the last poll is `max_poll_interval_ms` ago.
+    pub fn needs_poll(&self, ctx: &Context, name: &str) -> bool {
+        let interval =
+            Duration::from_millis(ctx.settings().max_poll_interval_ms);
+        match self.subscriptions.get(name) {
+            Some(subscribed) => subscribed
+                .last_poll
+                .is_none_or(|last_poll| last_poll.elapsed() >= interval),
+            None => true,
+        }
+    }
+
+    /// Remember that a subscribed user has just been polled.
+    pub fn polled(&mut self, name: &str) {
+        if let Some(subscribed) = self.subscriptions.get_mut(name) {
+            subscribed.last_poll = Some(Instant::now());
+        }
+    }
+
+    /// Handle pushed events until `timeout` has passed. Returns early, with
+    /// `true`, if a stop is requested through `stop`.
+    pub fn wait(
+        &mut self,
+        ctx: &Context,
+        states: &[Arc<Mutex<State>>],
+        transport: &dyn Transport,
+        timeout: Duration,
+        stop: &Receiver<()>,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if stop_requested(stop) {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            // Wait in short slices so that a stop is noticed quickly
+            let slice = (deadline - now).min(STOP_CHECK_INTERVAL);
+            match self.receiver.recv_timeout(slice) {
+                Ok(event) => self.handle(ctx, event, states, transport),
+                Err(RecvTimeoutError::Timeout) => {}
+                // We hold a sender ourselves, so this can't happen
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        event: PushEvent,
+        states: &[Arc<Mutex<State>>],
+        transport: &dyn Transport,
+    ) {
+        let user = match &event {
+            PushEvent::Blob { user, .. }
+            | PushEvent::Welcome { user }
+            | PushEvent::Disconnected { user } => user.clone(),
+        };
+        let state = match states
+            .iter()
+            .find(|state| state.lock().unwrap().name == user)
+        {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.lock().unwrap();
+        match event {
+            PushEvent::Blob {
+                group_id, index, ..
+            } => {
+                if let Some(group_state) = state.groups.get_mut(&group_id) {
+                    // We might have fetched the blob already
+                    if index < group_state.next_blob {
+                        return;
+                    }
+                    if let Err(err) =
+                        sync_group(ctx, transport, &group_id, group_state)
+                    {
+                        ctx.emit(Event::TransportError {
+                            error: format!("{}: {}", group_id, err),
+                        });
+                    }
+                }
+            }
+            PushEvent::Welcome { .. } => {
+                let checked = check_invitations(ctx, &mut state, transport);
+                if let Err(error) = checked {
+                    ctx.emit(Event::TransportError { error });
+                }
+            }
+            PushEvent::Disconnected { .. } => {
+                // Idle connections are closed by the transport, too, so
+                // this is expected now and then
+                debug!(
+                    target: "polling",
+                    "Lost the push connection for {}, subscribing again",
+                    user
+                );
+                self.subscriptions.remove(&user);
+                return;
+            }
+        }
+        if let Err(err) = persist(ctx, &state) {
+            error!(target: "polling", "Couldn't save the state: {}", err);
+        }
+    }
+}
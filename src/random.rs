@@ -0,0 +1,59 @@
+//! Client-side randomness.
+//!
+//! Normally everything comes from the system RNG. With `--seed` (or `seed`
+//! in `Settings.toml`), user names come from an RNG seeded with that number
+//! instead, so that a session picks the same names again.
+//!
+//! Nothing secret is drawn from the seeded RNG. Message nonces always come
+//! from the system RNG, since replaying a seed would reuse them with the
+//! same key. Keys and group IDs can't be seeded at all:
+//! `Identity::random`, `UserInitKeyBundle::new` and `GroupId::random` in
+//! melissa take no RNG and always use the system one.
+//!
+//! Polling jitter isn't seeded either: the poller runs on its own clock, so
+//! its draws would interleave differently with the REPL's on every run.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+pub struct Random {
+    seeded: Option<StdRng>,
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Random::new()
+    }
+}
+
+impl Random {
+    pub fn new() -> Random {
+        Random { seeded: None }
+    }
+
+    /// Derive all further randomness from `seed`.
+    pub fn seed(&mut self, seed: u64) {
+        self.seeded = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// A name like the ones from `names::Generator`, e.g. "busy-cat".
+    pub fn name(&mut self) -> String {
+        let mut pick = |words: &[&'static str]| {
+            let word = match &mut self.seeded {
+                Some(rng) => words.choose(rng),
+                None => words.choose(&mut rand::thread_rng()),
+            };
+            *word.unwrap()
+        };
+        let adjective = pick(names::ADJECTIVES);
+        let noun = pick(names::NOUNS);
+        format!("{}-{}", adjective, noun)
+    }
+}
+
+/// A seed for runs that weren't given one, so that their names can be
+/// picked again.
+pub fn new_seed() -> u64 {
+    rand::thread_rng().gen()
+}
@@ -3,31 +3,35 @@
 extern crate reqwest;
 extern crate serde_json;
 
+use std::collections::HashMap;
 use std::fmt;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
 
-use melissa::group;
-use melissa::keys;
-use melissa::messages;
 use rhai::*;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::collections::{hash_map, HashMap};
-use std::fs::File;
-use std::process::exit;
-use std::sync::{Arc, Mutex, MutexGuard};
 
-use crate::client::{append_blob, get_blobs, Blob, Blobs};
-use crate::message::Message;
-use crate::polling::process_message;
-use crate::state::{GroupState, State};
-use crate::utils::{read_codec, write_codec};
+use mls_client::client::{Blob, Blobs};
+use mls_client::events::{self, Event};
+use mls_client::groups::{
+    add_self_to_group, check_new_user, decline_invitation, init_user_keys,
+    persist, publish_key_package, rotate_identity,
+};
+use mls_client::message::Message;
+use mls_client::polling::verify_history;
+use mls_client::state::{State, UpdatePolicy};
+use mls_client::storage::{load_state, read_passphrase, set_passphrase};
+use mls_client::transport::Transport;
+use mls_client::users::{current, Users};
+use mls_client::{Context, MlsClient};
 
-use super::POLLING;
-use super::REPL;
+use super::{FAILURE, HANDLERS, LOGGER, POLLING, REPL};
 use serde::export::Formatter;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub enum REPLReturnType {
+    #[default]
     Unit,
     Boolean,
     Message,
@@ -40,12 +44,6 @@ pub enum REPLReturnType {
     StringsResult,
 }
 
-impl Default for REPLReturnType {
-    fn default() -> Self {
-        REPLReturnType::Unit
-    }
-}
-
 impl fmt::Display for REPLReturnType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -76,14 +74,14 @@ impl REPLDictionary {
         self.0.insert(
             name,
             REPLFunction {
-                name: name,
+                name,
                 description: "",
-                return_type: return_type.clone(),
+                return_type: *return_type,
             },
         );
     }
 
-    fn get_starts_with(&self, input: &String) -> Option<REPLReturnType> {
+    fn get_starts_with(&self, input: &str) -> Option<REPLReturnType> {
         self.0.iter().find_map(|(&name, &replf)| {
             if input.starts_with(name) {
                 Some(replf.return_type)
@@ -120,11 +118,67 @@ fn register_fn(name: &'static str, return_type: &REPLReturnType) {
 
 macro_rules! register_function {
     ($engine:expr, $func_name:expr, $func:expr, $return_type:expr) => {
-        $engine.register_fn($func_name, $func);
+        $engine.register_fn($func_name, Checked::checked($func));
         register_fn($func_name, &$return_type);
     };
 }
 
+/// A value returned by a registered function, which may be an error.
+pub trait Outcome {
+    fn error(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<T> Outcome for Result<T, String> {
+    fn error(&self) -> Option<String> {
+        self.as_ref().err().cloned()
+    }
+}
+
+impl Outcome for () {}
+impl Outcome for bool {}
+impl Outcome for String {}
+impl Outcome for Vec<String> {}
+impl Outcome for Message {}
+impl Outcome for Blob {}
+impl Outcome for Blobs {}
+
+/// A registered function that remembers the first error it returns in
+/// `FAILURE`, so that errors are noticed wherever in a command the
+/// function is called.
+pub trait Checked<ARGS, RET> {
+    type Function;
+    fn checked(self) -> Self::Function;
+}
+
+macro_rules! impl_checked {
+    ($($arg:ident: $ty:ident),*) => {
+        impl<FN, $($ty: 'static,)* RET> Checked<($($ty,)*), RET> for FN
+        where
+            FN: Fn($($ty),*) -> RET + 'static,
+            RET: Outcome + 'static,
+        {
+            type Function = Box<dyn Fn($($ty),*) -> RET>;
+
+            fn checked(self) -> Self::Function {
+                Box::new(move |$($arg),*| {
+                    let result = self($($arg),*);
+                    if let Some(err) = result.error() {
+                        FAILURE.lock().unwrap().get_or_insert(err);
+                    }
+                    result
+                })
+            }
+        }
+    };
+}
+
+impl_checked!();
+impl_checked!(a: A);
+impl_checked!(a: A, b: B);
+impl_checked!(a: A, b: B, c: C);
+
 pub fn register_types(engine: &mut Engine) {
     // All return types HAVE to be registered here, or else exception
     // handling won't work.
@@ -151,8 +205,14 @@ fn blob(index: i64, content: Message) -> Blob {
 // added anyway when doing polling).
 //
 // send(group_id, blob)
-fn send(group_id: String, blob: Blob) -> Result<(), String> {
-    append_blob(group_id.as_str(), &blob).map_err(|err| err.to_string())
+fn send(
+    transport: &dyn Transport,
+    group_id: String,
+    blob: Blob,
+) -> Result<(), String> {
+    transport
+        .append_blob(group_id.as_str(), &blob)
+        .map_err(|err| err.to_string())
 }
 
 // Fetch all blobs without adding them to the group state.
@@ -162,86 +222,124 @@ fn send(group_id: String, blob: Blob) -> Result<(), String> {
 // recv_to(group_id, to_index) -> Vec<Blob<Message>>
 // recv_from_to(group_id, from_index, to_index) -> Vec<Blob<Message>>
 
-fn recv(group_id: String) -> Result<Blobs, String> {
-    get_blobs(group_id.as_str(), None, None).map_err(|err| err.to_string())
-}
-
-fn recv_from(group_id: String, from: i64) -> Result<Blobs, String> {
-    get_blobs(group_id.as_str(), Some(from), None)
-        .map_err(|err| err.to_string())
-}
-
-fn recv_to(group_id: String, to: i64) -> Result<Blobs, String> {
-    get_blobs(group_id.as_str(), None, Some(to))
-        .map_err(|err| err.to_string())
-}
-
-fn recv_from_to(
+fn recv(
+    transport: &dyn Transport,
     group_id: String,
-    from: i64,
-    to: i64,
+    from: Option<i64>,
+    to: Option<i64>,
 ) -> Result<Blobs, String> {
-    get_blobs(group_id.as_str(), Some(from), Some(to))
+    transport
+        .get_blobs(group_id.as_str(), from, to)
         .map_err(|err| err.to_string())
 }
 
-pub fn register_functions(state: Arc<Mutex<State>>, engine: &mut Engine) {
+pub fn register_functions(
+    ctx: Arc<Context>,
+    users: Arc<Mutex<Users>>,
+    transport: Arc<dyn Transport>,
+    engine: &mut Engine,
+) {
     register_function!(engine, "blob", blob, REPLReturnType::Blob);
-    register_function!(engine, "send", send, REPLReturnType::UnitResult);
-    register_function!(engine, "recv", recv, REPLReturnType::BlobsResult);
+    let send_closure = |t: Arc<dyn Transport>| {
+        move |group_id: String, blob: Blob| -> Result<(), String> {
+            send(t.as_ref(), group_id, blob)
+        }
+    };
+    register_function!(
+        engine,
+        "send",
+        send_closure(transport.clone()),
+        REPLReturnType::UnitResult
+    );
+    let recv_closure = |t: Arc<dyn Transport>| {
+        move |group_id: String| -> Result<Blobs, String> {
+            recv(t.as_ref(), group_id, None, None)
+        }
+    };
+    register_function!(
+        engine,
+        "recv",
+        recv_closure(transport.clone()),
+        REPLReturnType::BlobsResult
+    );
+    let recv_from_closure = |t: Arc<dyn Transport>| {
+        move |group_id: String, from: i64| -> Result<Blobs, String> {
+            recv(t.as_ref(), group_id, Some(from), None)
+        }
+    };
     register_function!(
         engine,
         "recv_from",
-        recv_from,
+        recv_from_closure(transport.clone()),
         REPLReturnType::BlobsResult
     );
+    let recv_to_closure = |t: Arc<dyn Transport>| {
+        move |group_id: String, to: i64| -> Result<Blobs, String> {
+            recv(t.as_ref(), group_id, None, Some(to))
+        }
+    };
     register_function!(
         engine,
         "recv_to",
-        recv_to,
+        recv_to_closure(transport.clone()),
         REPLReturnType::BlobsResult
     );
+    let recv_from_to_closure = |t: Arc<dyn Transport>| {
+        move |group_id: String,
+              from: i64,
+              to: i64|
+              -> Result<Blobs, String> {
+            recv(t.as_ref(), group_id, Some(from), Some(to))
+        }
+    };
     register_function!(
         engine,
         "recv_from_to",
-        recv_from_to,
+        recv_from_to_closure(transport.clone()),
         REPLReturnType::BlobsResult
     );
 
     // Create a group with the user as a single member.
     //
     // create(group_id)
-    let create_closure = |state: Arc<Mutex<State>>| {
-        move |group_id: String| -> Result<(), String> {
-            create_group(state.clone(), group_id)
-        }
-    };
+    let create_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |group_id: String| -> Result<(), String> {
+                client(&c, &u, &t)
+                    .create_group(&group_id)
+                    .map_err(|e| e.to_string())
+            }
+        };
     register_function!(
         engine,
         "create",
-        create_closure(state.clone()),
+        create_closure(ctx.clone(), users.clone(), transport.clone()),
         REPLReturnType::UnitResult
     );
 
-    // Add a user to a group and generate an invitation file for them.
-    // Assumes that the user's data is stored in `<user>.pub` and
-    // `<user>.init`. Saves the welcome package to `<group>_<user>.welcome`.
+    // Add a user to a group and send them an invitation. Uses the key
+    // package the user has published on the server, or `<user>.pub` and
+    // `<user>.init` if there is none. The invitation goes to the user's
+    // mailbox on the server; if that fails, the welcome package is saved to
+    // `<group>_<user>.welcome` instead.
     //
     // add(group_id, user_name)
-    let add_closure = |state: Arc<Mutex<State>>| {
+    let add_closure = |c: Arc<Context>,
+                       u: Arc<Mutex<Users>>,
+                       t: Arc<dyn Transport>| {
         move |group_id: String, user_name: String| -> Result<(), String> {
-            add_to_group(state.clone(), group_id, user_name).map_err(
-                |err| {
+            client(&c, &u, &t)
+                .add(&group_id, &user_name)
+                .map_err(|err| {
                     println!("{}", err);
-                    err
-                },
-            )
+                    err.to_string()
+                })
         }
     };
     register_function!(
         engine,
         "add",
-        add_closure(state.clone()),
+        add_closure(ctx.clone(), users.clone(), transport.clone()),
         REPLReturnType::UnitResult
     );
 
@@ -250,74 +348,305 @@ pub fn register_functions(state: Arc<Mutex<State>>, engine: &mut Engine) {
     // `<user>.init`. Saves the welcome package to `<group>_<user>.welcome`.
     //
     // add_self(group_id)
-    let add_self_closure = |state: Arc<Mutex<State>>| {
-        move |group_id: String| -> Result<(), String> {
-            add_self_to_group(state.clone(), group_id).map_err(|err| {
-                println!("{}", err);
-                err
-            })
-        }
-    };
+    let add_self_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |group_id: String| -> Result<(), String> {
+                let state = current(&u);
+                add_self_to_group(&c, state.clone(), t.as_ref(), group_id)
+                    .and_then(|_| persist(&c, &state.lock().unwrap()))
+                    .map_err(|err| {
+                        println!("{}", err);
+                        err
+                    })
+            }
+        };
     register_function!(
         engine,
         "add_self",
-        add_self_closure(state.clone()),
+        add_self_closure(ctx.clone(), users.clone(), transport.clone()),
         REPLReturnType::UnitResult
     );
 
-    // Join a group. The welcome file has to be present.
+    // Join a group. Uses the invitation from the mailbox on the server, or
+    // the welcome file `<group>_<user>.welcome` if there is none.
     //
     // join(group_id)
-    let join_closure = |state: Arc<Mutex<State>>| {
+    let join_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |group_id: String| -> Result<(), String> {
+                client(&c, &u, &t)
+                    .join(&group_id)
+                    .map_err(|e| e.to_string())
+            }
+        };
+    register_function!(
+        engine,
+        "join",
+        join_closure(ctx.clone(), users.clone(), transport.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // List pending invitations from the mailbox on the server.
+    //
+    // invitations()
+    let invitations_closure =
+        |u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move || -> Result<Vec<String>, String> {
+                let s = current(&u);
+                let state = s.lock().unwrap();
+                let invitations = t
+                    .get_welcomes(&state.name)
+                    .map_err(|e| e.to_string())?;
+                Ok(invitations
+                    .iter()
+                    .map(|i| format!("{} (from {})", i.group_id, i.sender))
+                    .collect())
+            }
+        };
+    register_function!(
+        engine,
+        "invitations",
+        invitations_closure(users.clone(), transport.clone()),
+        REPLReturnType::StringsResult
+    );
+
+    // Choose whether to join groups automatically when an invitation
+    // arrives while polling.
+    //
+    // auto_join(enabled)
+    let auto_join_closure = |c: Arc<Context>| {
+        move |enabled: bool| {
+            c.settings_mut().auto_join = enabled;
+        }
+    };
+    register_function!(
+        engine,
+        "auto_join",
+        auto_join_closure(ctx.clone()),
+        REPLReturnType::Unit
+    );
+
+    // Show the effective settings, after the settings files, environment
+    // variables, command-line options and changes made in the REPL.
+    //
+    // settings()
+    let settings_closure =
+        |c: Arc<Context>| move || -> String { c.settings().describe() };
+    register_function!(
+        engine,
+        "settings",
+        settings_closure(ctx.clone()),
+        REPLReturnType::String
+    );
+
+    // Choose whether the server pushes notifications about new blobs while
+    // polling is on. Users are polled as before if the server can't push.
+    //
+    // push(enabled)
+    let push_closure = |c: Arc<Context>| {
+        move |enabled: bool| {
+            c.settings_mut().push = enabled;
+        }
+    };
+    register_function!(
+        engine,
+        "push",
+        push_closure(ctx.clone()),
+        REPLReturnType::Unit
+    );
+
+    // Decline an invitation to a group, removing it from the mailbox.
+    //
+    // decline(group_id)
+    let decline_closure = |u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
         move |group_id: String| -> Result<(), String> {
-            join_group(state.clone(), group_id)
+            let s = current(&u);
+            let state = s.lock().unwrap();
+            decline_invitation(&state, t.as_ref(), group_id)
         }
     };
     register_function!(
         engine,
-        "join",
-        join_closure(state.clone()),
+        "decline",
+        decline_closure(users.clone(), transport.clone()),
         REPLReturnType::UnitResult
     );
 
     // Do an update.
     //
     // update(group_id)
-    let update_closure = |s: Arc<Mutex<State>>| {
-        move |group_id: String| -> Result<(), String> {
+    let update_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |group_id: String| -> Result<(), String> {
+                client(&c, &u, &t)
+                    .update(&group_id)
+                    .map_err(|e| e.to_string())
+            }
+        };
+    register_function!(
+        engine,
+        "update",
+        update_closure(ctx.clone(), users.clone(), transport.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // Let the poller do updates in a group automatically, every `minutes`
+    // minutes and/or after `blobs` blobs have been processed since our last
+    // update. Zero turns a limit off.
+    //
+    // update_policy(group_id, minutes, blobs)
+    let update_policy_closure = |c: Arc<Context>, u: Arc<Mutex<Users>>| {
+        move |group_id: String,
+              minutes: i64,
+              blobs: i64|
+              -> Result<(), String> {
+            let s = current(&u);
             let mut state = s.lock().unwrap();
-            do_update(&mut state, group_id)
+            let group_state = state
+                .groups
+                .get_mut(&group_id)
+                .ok_or_else(|| "Unknown group!".to_string())?;
+            let limit = |n: i64| if n > 0 { Some(n as u64) } else { None };
+            group_state.update_policy = UpdatePolicy {
+                every_minutes: limit(minutes),
+                every_blobs: limit(blobs),
+            };
+            persist(&c, &state)
         }
     };
     register_function!(
         engine,
-        "update",
-        update_closure(state.clone()),
+        "update_policy",
+        update_policy_closure(ctx.clone(), users.clone()),
         REPLReturnType::UnitResult
     );
 
-    // Remove a user from the group. Assumes that the user's data is stored
-    // in `<user>.pub` and `<user>.init`.
+    // Remove a user from the group. Looks up the user's key like `add`.
     //
     // remove(group_id, user_name)
-    let remove_closure = |s: Arc<Mutex<State>>| {
+    let remove_closure = |c: Arc<Context>,
+                          u: Arc<Mutex<Users>>,
+                          t: Arc<dyn Transport>| {
         move |group_id: String, user_name: String| -> Result<(), String> {
-            let mut state = s.lock().unwrap();
-            remove_from_group(&mut state, group_id, user_name)
+            client(&c, &u, &t)
+                .remove(&group_id, &user_name)
+                .map_err(|e| e.to_string())
         }
     };
     register_function!(
         engine,
         "remove",
-        remove_closure(state.clone()),
+        remove_closure(ctx.clone(), users.clone(), transport.clone()),
         REPLReturnType::UnitResult
     );
 
+    // Send a text message to the group.
+    //
+    // say(group_id, text)
+    let say_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |group_id: String, text: String| -> Result<(), String> {
+                client(&c, &u, &t)
+                    .send(&group_id, &text)
+                    .map_err(|e| e.to_string())
+            }
+        };
+    register_function!(
+        engine,
+        "say",
+        say_closure(ctx.clone(), users.clone(), transport.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // See messages received in the group.
+    //
+    // inbox(group_id)
+    let inbox_closure = |u: Arc<Mutex<Users>>| {
+        move |group_id: String| -> Result<Vec<String>, String> {
+            let s = current(&u);
+            let state = s.lock().unwrap();
+            if let Some(group_state) = state.groups.get(&group_id) {
+                Ok(group_state
+                    .inbox
+                    .iter()
+                    .map(|m| {
+                        format!(
+                            "[{}] {} (unverified): {}",
+                            m.index, m.claimed_sender, m.text
+                        )
+                    })
+                    .collect())
+            } else {
+                Err("Unknown group!".into())
+            }
+        }
+    };
+    register_function!(
+        engine,
+        "inbox",
+        inbox_closure(users.clone()),
+        REPLReturnType::StringsResult
+    );
+
+    // Check the history we have processed against what the server has now.
+    // Returns the indices of blobs that don't match.
+    //
+    // verify(group_id)
+    let verify_closure = |c: Arc<Context>,
+                          u: Arc<Mutex<Users>>,
+                          t: Arc<dyn Transport>| {
+        move |group_id: String| -> Result<Vec<String>, String> {
+            let s = current(&u);
+            let mut state = s.lock().unwrap();
+            if let Some(group_state) = state.groups.get_mut(&group_id) {
+                let forks =
+                    verify_history(&c, t.as_ref(), &group_id, group_state)
+                        .map_err(|e| e.to_string())?;
+                persist(&c, &state)?;
+                Ok(forks.iter().map(|ix| ix.to_string()).collect())
+            } else {
+                Err("Unknown group!".into())
+            }
+        }
+    };
+    register_function!(
+        engine,
+        "verify",
+        verify_closure(ctx.clone(), users.clone(), transport.clone()),
+        REPLReturnType::StringsResult
+    );
+
+    // See the indices of blobs where a fork was detected so far.
+    //
+    // forks(group_id)
+    let forks_closure = |u: Arc<Mutex<Users>>| {
+        move |group_id: String| -> Result<Vec<String>, String> {
+            let s = current(&u);
+            let state = s.lock().unwrap();
+            if let Some(group_state) = state.groups.get(&group_id) {
+                Ok(group_state
+                    .forks
+                    .iter()
+                    .map(|ix| ix.to_string())
+                    .collect())
+            } else {
+                Err("Unknown group!".into())
+            }
+        }
+    };
+    register_function!(
+        engine,
+        "forks",
+        forks_closure(users.clone()),
+        REPLReturnType::StringsResult
+    );
+
     // See group's roster.
     //
     // roster(group_id)
-    let roster_closure = |s: Arc<Mutex<State>>| {
+    let roster_closure = |u: Arc<Mutex<Users>>| {
         move |group_id: String| -> Result<Vec<String>, String> {
+            let s = current(&u);
             let state = s.lock().unwrap();
             if let Some(group_state) = state.groups.get(&group_id) {
                 Ok(group_state
@@ -336,15 +665,16 @@ pub fn register_functions(state: Arc<Mutex<State>>, engine: &mut Engine) {
     register_function!(
         engine,
         "roster",
-        roster_closure(state.clone()),
+        roster_closure(users.clone()),
         REPLReturnType::StringsResult
     );
 
     // List groups.
     //
     // list()
-    let list_closure = |s: Arc<Mutex<State>>| {
+    let list_closure = |u: Arc<Mutex<Users>>| {
         move || -> Result<Vec<String>, String> {
+            let s = current(&u);
             let state = s.lock().unwrap();
             Ok(state.groups.keys().cloned().collect())
         }
@@ -352,59 +682,274 @@ pub fn register_functions(state: Arc<Mutex<State>>, engine: &mut Engine) {
     register_function!(
         engine,
         "list",
-        list_closure(state.clone()),
+        list_closure(users.clone()),
         REPLReturnType::StringsResult
     );
 
-    // Load state from disk (from `<user>.state`).
+    // Load state from disk (from `<user>.state` in the data directory) and
+    // switch to the loaded user. Like at startup, the state is saved again
+    // in the current format, and the user's keys are written and published.
     //
     // load(user_name)
-    let load_closure = |s: Arc<Mutex<State>>| {
+    let load_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |user_name: String| -> Result<(), String> {
+                let state = load_state(
+                    c.data_path(format!("{}.state", user_name)),
+                    || read_passphrase("Passphrase: "),
+                )?;
+                println!("Loaded {}", state.name);
+                persist(&c, &state)?;
+                init_user_keys(&c, &state, t.as_ref())?;
+                u.lock().unwrap().insert(state);
+                Ok(())
+            }
+        };
+    register_function!(
+        engine,
+        "load",
+        load_closure(ctx.clone(), users.clone(), transport.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // Create another local user, publish their keys and switch to them.
+    //
+    // new_user(user_name)
+    let new_user_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move |user_name: String| -> Result<(), String> {
+                if u.lock().unwrap().contains(&user_name) {
+                    return Err("User already exists!".into());
+                }
+                check_new_user(&c, &user_name)?;
+                let mut state = State::new(&user_name);
+                let passphrase = read_passphrase(&format!(
+                    "Passphrase for {} (empty for none): ",
+                    user_name
+                ))?;
+                set_passphrase(&mut state, &passphrase)?;
+                persist(&c, &state)?;
+                init_user_keys(&c, &state, t.as_ref())?;
+                u.lock().unwrap().insert(state);
+                Ok(())
+            }
+        };
+    register_function!(
+        engine,
+        "new_user",
+        new_user_closure(ctx.clone(), users.clone(), transport.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // Run the following commands as another local user.
+    //
+    // switch(user_name)
+    // as(user_name)
+    let switch_closure = |u: Arc<Mutex<Users>>| {
         move |user_name: String| -> Result<(), String> {
+            u.lock().unwrap().switch(&user_name)
+        }
+    };
+    register_function!(
+        engine,
+        "switch",
+        switch_closure(users.clone()),
+        REPLReturnType::UnitResult
+    );
+    register_function!(
+        engine,
+        "as",
+        switch_closure(users.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // List local users.
+    //
+    // users()
+    let users_closure = |u: Arc<Mutex<Users>>| {
+        move || -> Result<Vec<String>, String> {
+            Ok(u.lock().unwrap().names())
+        }
+    };
+    register_function!(
+        engine,
+        "users",
+        users_closure(users.clone()),
+        REPLReturnType::StringsResult
+    );
+
+    // See the name of the current user.
+    //
+    // whoami()
+    let whoami_closure = |u: Arc<Mutex<Users>>| {
+        move || -> String { u.lock().unwrap().current_name().into() }
+    };
+    register_function!(
+        engine,
+        "whoami",
+        whoami_closure(users.clone()),
+        REPLReturnType::String
+    );
+
+    // Switch to a new identity in all groups and publish the new keys. Call
+    // again to finish if some groups failed.
+    //
+    // rotate_identity()
+    let rotate_identity_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+            move || -> Result<(), String> {
+                let s = current(&u);
+                let mut state = s.lock().unwrap();
+                rotate_identity(&c, &mut state, t.as_ref())
+            }
+        };
+    register_function!(
+        engine,
+        "rotate_identity",
+        rotate_identity_closure(
+            ctx.clone(),
+            users.clone(),
+            transport.clone()
+        ),
+        REPLReturnType::UnitResult
+    );
+
+    // Publish the user's key package on the server again.
+    //
+    // publish()
+    let publish_closure = |u: Arc<Mutex<Users>>, t: Arc<dyn Transport>| {
+        move || -> Result<(), String> {
+            let s = current(&u);
+            let state = s.lock().unwrap();
+            publish_key_package(&state, t.as_ref())
+        }
+    };
+    register_function!(
+        engine,
+        "publish",
+        publish_closure(users.clone(), transport.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // Set a new passphrase for the state file and save it right away. An
+    // empty passphrase stores the state unencrypted.
+    //
+    // rekey()
+    let rekey_closure = |c: Arc<Context>, u: Arc<Mutex<Users>>| {
+        move || -> Result<(), String> {
+            let s = current(&u);
             let mut state = s.lock().unwrap();
-            let file = File::open(format!("{}.state", user_name))
-                .map_err(|e| e.to_string())?;
-            *state =
-                serde_json::from_reader(file).map_err(|e| e.to_string())?;
-            println!("Loaded {}", state.name);
+            let passphrase = read_passphrase("New passphrase: ")?;
+            if passphrase != read_passphrase("Repeat passphrase: ")? {
+                return Err("Passphrases don't match!".into());
+            }
+            set_passphrase(&mut state, &passphrase)?;
+            persist(&c, &state)
+        }
+    };
+    register_function!(
+        engine,
+        "rekey",
+        rekey_closure(ctx.clone(), users.clone()),
+        REPLReturnType::UnitResult
+    );
+
+    // Call a script function for every event of a kind, e.g.
+    // `fn greet(event) { print(event) }` and `on("member_added", "greet")`.
+    // The function gets the event as a JSON string. Handlers run between
+    // commands. Kinds: blob_received, member_added, member_removed,
+    // key_updated, message_received, fork_detected, transport_error,
+    // invitation_received.
+    //
+    // on(kind, function_name)
+    register_function!(
+        engine,
+        "on",
+        |kind: String, function: String| {
+            HANDLERS.lock().unwrap().add(kind, function);
+        },
+        REPLReturnType::Unit
+    );
+
+    // Append all events to a file, as JSON, one event per line.
+    //
+    // log_events(path)
+    let log_events_closure = |c: Arc<Context>| {
+        move |path: String| -> Result<(), String> {
+            c.subscribe(events::log_file(&path)?);
             Ok(())
         }
     };
     register_function!(
         engine,
-        "load",
-        load_closure(state.clone()),
+        "log_events",
+        log_events_closure(ctx.clone()),
         REPLReturnType::UnitResult
     );
 
-    // Quit the program.
+    // See or change how much diagnostic output there is, either for
+    // everything or for one of "transport", "polling", "crypto" and "repl".
+    // Levels are "off", "error", "warn", "info", "debug" and "trace".
+    // Returns the resulting levels.
+    //
+    // log_level()
+    // log_level(level)
+    // log_level(target, level)
+    register_function!(
+        engine,
+        "log_level",
+        || -> Result<Vec<String>, String> { Ok(LOGGER.levels()) },
+        REPLReturnType::StringsResult
+    );
+    register_function!(
+        engine,
+        "log_level",
+        |level: String| -> Result<Vec<String>, String> {
+            LOGGER.set_level(None, &level)?;
+            Ok(LOGGER.levels())
+        },
+        REPLReturnType::StringsResult
+    );
+    register_function!(
+        engine,
+        "log_level",
+        |target: String, level: String| -> Result<Vec<String>, String> {
+            LOGGER.set_level(Some(&target), &level)?;
+            Ok(LOGGER.levels())
+        },
+        REPLReturnType::StringsResult
+    );
+
+    // Quit the program, after stopping polling and saving all users.
     //
     // quit()
     // exit()
+    let quit_closure =
+        |c: Arc<Context>, u: Arc<Mutex<Users>>| move || quit(&c, &u);
     register_function!(
         engine,
         "quit",
-        || {
-            exit(0);
-        },
+        quit_closure(ctx.clone(), users.clone()),
         REPLReturnType::Unit
     );
     register_function!(
         engine,
         "exit",
-        || {
-            exit(0);
-        },
+        quit_closure(ctx.clone(), users.clone()),
         REPLReturnType::Unit
     );
 
-    // Start querying the server for data
+    // Start querying the server for data, for all local users
     register_function!(
         engine,
         "start_poll",
         move || {
             let mut poll = POLLING.lock().unwrap();
-            poll.start_polling(state.clone());
+            poll.start_polling(
+                ctx.clone(),
+                users.clone(),
+                transport.clone(),
+            );
         },
         REPLReturnType::Unit
     );
@@ -420,6 +965,19 @@ pub fn register_functions(state: Arc<Mutex<State>>, engine: &mut Engine) {
         REPLReturnType::Unit
     );
 
+    // See how often we poll, when the next poll is due and the last error.
+    //
+    // poll_status()
+    register_function!(
+        engine,
+        "poll_status",
+        || {
+            let poll = POLLING.lock().unwrap();
+            poll.status()
+        },
+        REPLReturnType::String
+    );
+
     register_function!(
         engine,
         "is_polling",
@@ -441,280 +999,221 @@ pub fn register_functions(state: Arc<Mutex<State>>, engine: &mut Engine) {
     );
 }
 
-fn add_self_to_group(
-    st: Arc<Mutex<State>>,
-    group_id: String,
-) -> Result<(), String> {
-    let mut state = st.lock().unwrap();
-    let name = state.name.clone();
-    _add_to_group(&mut state, group_id, name.as_str())
+/// The current user as an `MlsClient`.
+fn client(
+    ctx: &Arc<Context>,
+    users: &Arc<Mutex<Users>>,
+    transport: &Arc<dyn Transport>,
+) -> MlsClient {
+    MlsClient::with_state(ctx.clone(), current(users), transport.clone())
 }
 
-fn add_to_group(
-    st: Arc<Mutex<State>>,
-    group_id: String,
-    user_name: String,
-) -> Result<(), String> {
-    let mut state = st.lock().unwrap();
-    _add_to_group(&mut state, group_id, user_name.as_str())
+/// Shut down and exit.
+fn quit(ctx: &Context, users: &Arc<Mutex<Users>>) {
+    shutdown(ctx, users);
+    exit(0)
 }
 
-fn _add_to_group(
-    state: &mut MutexGuard<State>,
-    group_id: String,
-    user_name: &str,
-) -> Result<(), String> {
-    println!("add to group {}, {}", group_id, user_name);
-    if let hash_map::Entry::Occupied(entry_group_state) =
-        state.groups.entry(group_id.clone())
-    {
-        let group_state = entry_group_state.into_mut();
-        // Read user info
-        let credential = read_codec(format!("{}.pub", user_name))
-            .map_err(|e| e.to_string())?;
-        let init_key = read_codec(format!("{}.init", user_name))
-            .map_err(|e| e.to_string())?;
-        // Generate a welcome package
-        let (welcome, add_raw) =
-            group_state.crypto.create_add(credential, &init_key);
-        let add_op = messages::GroupOperation {
-            msg_type: messages::GroupOperationType::Add,
-            group_operation: messages::GroupOperationValue::Add(add_raw),
-        };
-        // Process the add operation
-        let blob = Blob {
-            index: group_state.next_blob,
-            content: Message(group_state.crypto.create_handshake(add_op)),
-        };
-        process_message(&group_id, group_state, blob.clone());
-        // Send the operation;
-        // TODO restart if sending fails
-        append_blob(&group_id, &blob).map_err(|e| e.to_string())?;
-        // Save the welcome package
-        write_codec(
-            format!("{}_{}.welcome", group_id, user_name),
-            &welcome,
-        )
-        .map_err(|e| e.to_string())?;
-        println!("Wrote {}_{}.welcome", group_id, user_name);
-        Ok(())
-    } else {
-        Err("Group doesn't exist!".into())
+/// Stop polling, waiting for the current poll to finish, and save the
+/// state of every user.
+pub fn shutdown(ctx: &Context, users: &Arc<Mutex<Users>>) {
+    POLLING.lock().unwrap().stop_polling();
+    let states = users.lock().unwrap().all();
+    for state in states {
+        let state = state.lock().unwrap();
+        if let Err(err) = persist(ctx, &state) {
+            error!(target: "repl", "Couldn't save {}: {}", state.name, err);
+        }
     }
 }
 
-fn join_group(
-    st: Arc<Mutex<State>>,
-    group_id: String,
-) -> Result<(), String> {
-    let mut state = st.lock().unwrap();
-    let state_name = state.name.clone();
-    let identity = state.identity.clone();
-    if let hash_map::Entry::Vacant(entry_group_state) =
-        state.groups.entry(group_id.clone())
-    {
-        // Import the group
-        let welcome: messages::Welcome =
-            read_codec(format!("{}_{}.welcome", group_id, state_name))
-                .map_err(|e| e.to_string())?;
-        let group_crypto =
-            group::Group::new_from_welcome(identity, &welcome);
-        let group_state = GroupState {
-            crypto: group_crypto,
-            // TODO: this will break if blobs can include things other than group operations
-            next_blob: welcome.transcript.len() as i64,
-        };
-        entry_group_state.insert(group_state);
-        Ok(())
-    } else {
-        Err("You're already a member of the group!".into())
+/// Script functions to call for events, by event kind. Events are queued
+/// when they happen and handled between commands, because the script
+/// engine is busy while a command runs.
+pub struct ScriptHandlers {
+    handlers: Vec<(String, String)>,
+    queue: Vec<Event>,
+}
+
+impl ScriptHandlers {
+    pub fn new() -> ScriptHandlers {
+        ScriptHandlers {
+            handlers: Vec::new(),
+            queue: Vec::new(),
+        }
+    }
+
+    /// Call the script function `function` for every event of `kind`.
+    pub fn add(&mut self, kind: String, function: String) {
+        self.handlers.push((kind, function));
+    }
+
+    /// Take the queued events, paired with the functions that handle them.
+    pub fn take(&mut self) -> Vec<(String, Event)> {
+        let handlers = &self.handlers;
+        self.queue
+            .drain(..)
+            .flat_map(|event| {
+                handlers
+                    .iter()
+                    .filter(|(kind, _)| kind == event.kind())
+                    .map(|(_, function)| (function.clone(), event.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 }
 
-fn do_update(state: &mut State, group_id: String) -> Result<(), String> {
-    if let hash_map::Entry::Occupied(entry_group_state) =
-        state.groups.entry(group_id.clone())
+/// Subscriber that queues events that script handlers are waiting for.
+pub fn queue_for_scripts(event: &Event) {
+    let mut handlers = HANDLERS.lock().unwrap();
+    if handlers
+        .handlers
+        .iter()
+        .any(|(kind, _)| kind == event.kind())
     {
-        let group_state = entry_group_state.into_mut();
-        let update_op = messages::GroupOperation {
-            msg_type: messages::GroupOperationType::Update,
-            group_operation: messages::GroupOperationValue::Update(
-                group_state.crypto.create_update(),
-            ),
-        };
-        let blob = Blob {
-            index: group_state.next_blob,
-            content: Message(
-                group_state.crypto.create_handshake(update_op),
-            ),
-        };
-        process_message(&group_id, group_state, blob.clone());
-        // TODO: we should try resending the blob if the sending fails.
-        append_blob(&group_id, &blob).map_err(|e| e.to_string())?;
-        Ok(())
-    } else {
-        Err("Group doesn't exist!".into())
+        handlers.queue.push(event.clone());
     }
 }
 
-fn remove_from_group(
-    state: &mut State,
-    group_id: String,
-    user_name: String,
-) -> Result<(), String> {
-    if let hash_map::Entry::Occupied(entry_group_state) =
-        state.groups.entry(group_id.clone())
-    {
-        let group_state = entry_group_state.into_mut();
-        // Find the user; we can't find them by username because we don't
-        // get usernames from add operations, so we have to look at the key
-        let credential: keys::BasicCredential =
-            read_codec(format!("{}.pub", user_name))
-                .map_err(|e| e.to_string())?;
-        let slot = group_state
-            .crypto
-            .get_members()
-            .iter()
-            .position(|k| k.public_key == credential.public_key);
-        if let Some(slot) = slot {
-            // Create a remove operation
-            let remove_raw = group_state.crypto.create_remove(slot);
-            let remove_op = messages::GroupOperation {
-                msg_type: messages::GroupOperationType::Remove,
-                group_operation: messages::GroupOperationValue::Remove(
-                    remove_raw,
-                ),
-            };
-            // Process the remove operation
-            let blob = Blob {
-                index: group_state.next_blob,
-                content: Message(
-                    group_state.crypto.create_handshake(remove_op),
-                ),
-            };
-            process_message(&group_id, group_state, blob.clone());
-            // Send the operation;
-            // TODO restart if sending fails
-            append_blob(&group_id, &blob).map_err(|e| e.to_string())?;
-            Ok(())
-        } else {
-            Err("User not found!".into())
+/// Evaluate a single command and print its result. Returns `false` if the
+/// command couldn't be evaluated, returned an error or called a function
+/// that returned an error.
+fn eval_command(
+    engine: &mut Engine,
+    scope: &mut Scope,
+    line: &str,
+) -> bool {
+    FAILURE.lock().unwrap().take();
+    let command = REPL.lock().unwrap().get_starts_with(line);
+    let result = match command {
+        Some(REPLReturnType::Boolean) => {
+            engine.eval_with_scope::<bool>(scope, line).map(|res| {
+                println!("res: {}", res);
+                true
+            })
+        }
+        Some(REPLReturnType::Message) => {
+            engine.eval_with_scope::<Message>(scope, line).map(|res| {
+                println!("res: {:?}", res);
+                true
+            })
+        }
+        Some(REPLReturnType::Blob) => {
+            engine.eval_with_scope::<Blob>(scope, line).map(|res| {
+                println!("res: {:?}", res);
+                true
+            })
+        }
+        Some(REPLReturnType::Blobs) => {
+            engine.eval_with_scope::<Blobs>(scope, line).map(|res| {
+                println!("res: {:?}", res);
+                true
+            })
+        }
+        Some(REPLReturnType::String) => {
+            engine.eval_with_scope::<String>(scope, line).map(|res| {
+                println!("res: {}", res.as_str());
+                true
+            })
+        }
+        Some(REPLReturnType::Strings) => engine
+            .eval_with_scope::<Vec<String>>(scope, line)
+            .map(|res| {
+                println!("res: {:?}", res);
+                true
+            }),
+        Some(REPLReturnType::UnitResult) => engine
+            .eval_with_scope::<Result<(), String>>(scope, line)
+            .map(|res| match res {
+                Err(e) => {
+                    println!("Error: {}", e);
+                    false
+                }
+                _ => true,
+            }),
+        Some(REPLReturnType::StringsResult) => engine
+            .eval_with_scope::<Result<Vec<String>, String>>(scope, line)
+            .map(|res| match res {
+                Ok(strings) => {
+                    println!("res: {:?}", strings);
+                    true
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    false
+                }
+            }),
+        Some(REPLReturnType::BlobsResult) => engine
+            .eval_with_scope::<Result<Blobs, String>>(scope, line)
+            .map(|res| match res {
+                Ok(blobs) => {
+                    println!("res: {:?}", blobs);
+                    true
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    false
+                }
+            }),
+        _ => engine.consume_with_scope(scope, line).map(|_| true),
+    };
+    let failure = FAILURE.lock().unwrap().take();
+    run_handlers(engine);
+    match (result, failure) {
+        (Ok(false), _) => false,
+        (Ok(true), None) => true,
+        (Ok(true), Some(e)) => {
+            // The command itself succeeded, but something it called didn't
+            println!("Error: {}", e);
+            false
+        }
+        (Err(e), _) => {
+            println!("Error: {}", e);
+            false
         }
-    } else {
-        Err("Group doesn't exist!".into())
     }
 }
 
-fn create_group(
-    state: Arc<Mutex<State>>,
-    group_id: String,
-) -> Result<(), String> {
-    let mut st = state.lock().unwrap();
-    let identity = st.identity.clone();
-    let credential = st.credential.clone();
-    match st.groups.entry(group_id) {
-        hash_map::Entry::Occupied(_) => Err("Group already exists!".into()),
-        hash_map::Entry::Vacant(slot) => {
-            let group_crypto = group::Group::new(
-                identity,
-                credential,
-                group::GroupId::random(),
-            );
-            slot.insert(GroupState {
-                next_blob: 0,
-                crypto: group_crypto,
-            });
-            Ok(())
+/// How many times handlers may trigger events that have handlers in turn.
+const MAX_HANDLER_ROUNDS: usize = 10;
+
+/// Call the script functions registered with `on()` for the events that
+/// have happened since the last command. Every function gets the event as
+/// a JSON string. The function is called directly rather than through a
+/// generated script, so the event's content can't change what is run.
+fn run_handlers(engine: &Engine) {
+    for _ in 0..MAX_HANDLER_ROUNDS {
+        let calls = HANDLERS.lock().unwrap().take();
+        if calls.is_empty() {
+            return;
+        }
+        for (function, event) in calls {
+            let mut json = serde_json::to_string(&event).unwrap();
+            let args = vec![&mut json as &mut dyn Any];
+            let result = engine.call_fn_raw(function.clone(), args);
+            let failure = FAILURE.lock().unwrap().take();
+            if let Err(err) = result {
+                println!("Error in {}: {}", function, err);
+            } else if let Some(err) = failure {
+                println!("Error in {}: {}", function, err);
+            }
         }
     }
+    println!("Event handlers keep triggering each other, giving up");
 }
 
-pub fn start(engine: &mut Engine) {
+pub fn start(engine: &mut Engine, ctx: &Context, users: Arc<Mutex<Users>>) {
     // Start the REPL
     let mut scope = rhai::Scope::new();
     let mut rl = Editor::<()>::new();
     loop {
-        let readline = rl.readline("> ");
+        let prompt = format!("{}> ", users.lock().unwrap().current_name());
+        let readline = rl.readline(&prompt);
         match readline {
             Ok(line) => {
                 rl.add_history_entry(&line);
-                let command = REPL.lock().unwrap().get_starts_with(&line);
-                let result = match command {
-                    Some(REPLReturnType::Boolean) => engine
-                        .eval_with_scope::<bool>(&mut scope, &line)
-                        .map(|res| {
-                            println!("res: {}", res);
-                            ()
-                        }),
-                    Some(REPLReturnType::Message) => engine
-                        .eval_with_scope::<Message>(&mut scope, &line)
-                        .map(|res| {
-                            println!("res: {:?}", res);
-                            ()
-                        }),
-                    Some(REPLReturnType::Blob) => engine
-                        .eval_with_scope::<Blob>(&mut scope, &line)
-                        .map(|res| {
-                            println!("res: {:?}", res);
-                            ()
-                        }),
-                    Some(REPLReturnType::Blobs) => engine
-                        .eval_with_scope::<Blobs>(&mut scope, &line)
-                        .map(|res| {
-                            println!("res: {:?}", res);
-                            ()
-                        }),
-                    Some(REPLReturnType::String) => engine
-                        .eval_with_scope::<String>(&mut scope, &line)
-                        .map(|res| {
-                            println!("res: {}", res.as_str());
-                            ()
-                        }),
-                    Some(REPLReturnType::Strings) => engine
-                        .eval_with_scope::<Vec<String>>(&mut scope, &line)
-                        .map(|res| {
-                            println!("res: {:?}", res);
-                            ()
-                        }),
-                    Some(REPLReturnType::UnitResult) => engine
-                        .eval_with_scope::<Result<(), String>>(
-                            &mut scope, &line,
-                        )
-                        .map(|res| match res {
-                            Err(e) => {
-                                println!("Error: {}", e);
-                            }
-                            _ => {}
-                        }),
-                    Some(REPLReturnType::StringsResult) => engine
-                        .eval_with_scope::<Result<Vec<String>, String>>(
-                            &mut scope, &line,
-                        )
-                        .map(|res| match res {
-                            Ok(strings) => {
-                                println!("res: {:?}", strings);
-                            }
-                            Err(e) => {
-                                println!("Error: {}", e);
-                            }
-                        }),
-                    Some(REPLReturnType::BlobsResult) => engine
-                        .eval_with_scope::<Result<Blobs, String>>(
-                            &mut scope, &line,
-                        )
-                        .map(|res| match res {
-                            Ok(blobs) => {
-                                println!("res: {:?}", blobs);
-                            }
-                            Err(e) => {
-                                println!("Error: {}", e);
-                            }
-                        }),
-                    _ => engine.consume_with_scope(&mut scope, &line),
-                };
-                if let Err(e) = result {
-                    println!("Error: {}", e)
-                }
+                eval_command(engine, &mut scope, &line);
             }
             Err(ReadlineError::Interrupted) => {
                 break;
@@ -728,4 +1227,178 @@ pub fn start(engine: &mut Engine) {
             }
         }
     }
+    shutdown(ctx, &users);
+}
+
+/// Run a script non-interactively, command by command, stopping at the
+/// first command that fails. A command fails as well if a function it
+/// calls returns an error, e.g. in a loop or an assignment. Returns
+/// `false` if a command failed.
+pub fn run_script(
+    engine: &mut Engine,
+    users: Arc<Mutex<Users>>,
+    source: &str,
+) -> bool {
+    let mut scope = rhai::Scope::new();
+    for command in split_commands(source) {
+        println!("{}> {}", users.lock().unwrap().current_name(), command);
+        if !eval_command(engine, &mut scope, &command) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Split a script into commands. Every line is a command, except that
+/// lines are joined until all braces are closed, so that loops and
+/// function definitions can span several lines. Braces in strings and
+/// comments don't count. Empty lines and `//` comments are skipped.
+fn split_commands(source: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut scanner = Scanner::default();
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if current.is_empty()
+            && (trimmed.is_empty() || trimmed.starts_with("//"))
+        {
+            continue;
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(trimmed);
+        scanner.scan(trimmed);
+        if scanner.is_complete() {
+            commands.push(current.clone());
+            current.clear();
+            scanner = Scanner::default();
+        }
+    }
+    if !current.is_empty() {
+        commands.push(current);
+    }
+    commands
+}
+
+/// How far a script is nested at the end of the lines scanned so far
+#[derive(Default)]
+struct Scanner {
+    /// Open braces.
+    depth: i64,
+    /// Open `/* */` comments, which can be nested.
+    comments: usize,
+}
+
+impl Scanner {
+    /// Count the braces on a line that are outside of strings, characters
+    /// and comments. Strings end with the line.
+    fn scan(&mut self, line: &str) {
+        let mut quote = None;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            let next = chars.peek().cloned();
+            if let Some(open) = quote {
+                if c == '\\' {
+                    chars.next();
+                } else if c == open {
+                    quote = None;
+                }
+            } else if self.comments > 0 {
+                if c == '*' && next == Some('/') {
+                    chars.next();
+                    self.comments -= 1;
+                } else if c == '/' && next == Some('*') {
+                    chars.next();
+                    self.comments += 1;
+                }
+            } else {
+                match (c, next) {
+                    ('"', _) | ('\'', _) => quote = Some(c),
+                    ('/', Some('/')) => return,
+                    ('/', Some('*')) => {
+                        chars.next();
+                        self.comments += 1;
+                    }
+                    ('{', _) => self.depth += 1,
+                    ('}', _) => self.depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Whether the lines scanned so far make up a whole command.
+    fn is_complete(&self) -> bool {
+        self.depth <= 0 && self.comments == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rhai::{Engine, RegisterFn, Scope};
+
+    use super::{
+        eval_command, register_fn, register_types, split_commands, Checked,
+        REPLReturnType,
+    };
+
+    #[test]
+    fn errors_fail_the_command_wherever_they_happen() {
+        let mut engine = Engine::new();
+        register_types(&mut engine);
+        register_function!(
+            engine,
+            "fail_on",
+            |x: i64| -> Result<(), String> {
+                if x == 2 {
+                    Err("two".into())
+                } else {
+                    Ok(())
+                }
+            },
+            REPLReturnType::UnitResult
+        );
+        let mut scope = Scope::new();
+        assert!(eval_command(&mut engine, &mut scope, "fail_on(1)"));
+        assert!(!eval_command(&mut engine, &mut scope, "fail_on(2)"));
+        assert!(!eval_command(
+            &mut engine,
+            &mut scope,
+            "let r = fail_on(2)"
+        ));
+        let source = "let i = 0; while i < 3 { fail_on(i); i = i + 1; }";
+        assert!(!eval_command(&mut engine, &mut scope, source));
+        assert!(eval_command(
+            &mut engine,
+            &mut scope,
+            "let r = fail_on(3)"
+        ));
+    }
+
+    #[test]
+    fn split_commands_joins_blocks() {
+        let source = "create(\"g\")\n\nfn f(x) {\n  say(x)\n}\n// done\n";
+        assert_eq!(
+            split_commands(source),
+            vec!["create(\"g\")", "fn f(x) {\nsay(x)\n}"]
+        );
+    }
+
+    #[test]
+    fn split_commands_ignores_braces_in_strings_and_comments() {
+        let source = "say(\"g\", \"{ \\\" {\")\n\
+                      say(\"g\", \"}\") // }\n\
+                      if true { /* } */\n\
+                      say(\"g\", '{')\n\
+                      }";
+        assert_eq!(
+            split_commands(source),
+            vec![
+                "say(\"g\", \"{ \\\" {\")",
+                "say(\"g\", \"}\") // }",
+                "if true { /* } */\nsay(\"g\", '{')\n}",
+            ]
+        );
+    }
 }
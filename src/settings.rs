@@ -1,16 +1,122 @@
 extern crate lazy_static;
 
-use config::{Config, ConfigError, File};
+use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Clone)]
+use config::{Config, ConfigError, Environment, File};
+
+/// Client configuration. Each layer overrides the ones before it:
+///
+/// 1. the defaults below;
+/// 2. `Settings.toml`, if there is one;
+/// 3. `Settings.<profile>.toml` for the chosen profile, e.g. `local` or
+///    `staging`;
+/// 4. environment variables with the `MLS_` prefix, e.g. `MLS_SERVER` or
+///    `MLS_POLL_INTERVAL_MS`;
+/// 5. command-line options.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Settings {
     pub server: String,
+    /// The profile whose settings file was loaded, if any.
+    pub profile: Option<String>,
+    /// Directory for keys, welcome packages and state files.
+    pub data_dir: String,
+    /// How long to wait for the server to answer a request.
+    pub request_timeout_secs: u64,
+    /// How many blobs from the future we keep per group while waiting for
+    /// the blobs before them.
+    pub max_buffered_blobs: usize,
+    /// Whether to join groups automatically when an invitation arrives
+    /// while polling.
+    pub auto_join: bool,
+    /// Join automatically only when invited by one of these users. Empty
+    /// means anyone.
+    pub auto_join_from: Vec<String>,
+    /// Whether to have the server push notifications about new blobs
+    /// instead of polling every second.
+    pub push: bool,
+    /// How often to poll when nothing is happening.
+    pub poll_interval_ms: u64,
+    /// How often to poll right after something arrived.
+    pub min_poll_interval_ms: u64,
+    /// The longest we wait between polls when the server keeps failing.
+    pub max_poll_interval_ms: u64,
+    /// Whether to write the user's credential and an init key to
+    /// `<user>.pub` and `<user>.init`, for adders whose server doesn't
+    /// store key packages.
+    pub key_files: bool,
+    /// Seed for the user names picked by the client. See `random.rs`.
+    pub seed: Option<u64>,
+    /// Level of diagnostic output: "off", "error", "warn", "info", "debug"
+    /// or "trace". See `logging.rs`.
+    pub log_level: String,
+    /// Levels for single parts of the client, e.g. `transport = "debug"`.
+    pub log_levels: HashMap<String, String>,
+    /// File to write diagnostic output to as JSON, one record per line.
+    pub log_file: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            server: "http://127.0.0.1:10100".into(),
+            profile: None,
+            data_dir: ".".into(),
+            request_timeout_secs: 30,
+            max_buffered_blobs: 100,
+            auto_join: false,
+            auto_join_from: Vec::new(),
+            push: false,
+            poll_interval_ms: 1000,
+            min_poll_interval_ms: 250,
+            max_poll_interval_ms: 60_000,
+            key_files: true,
+            seed: None,
+            log_level: "info".into(),
+            log_levels: HashMap::new(),
+            log_file: None,
+        }
+    }
 }
 
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
+    /// Load the settings for `profile`, or for the profile in `MLS_PROFILE`
+    /// if none is given. The profile's file has to exist.
+    pub fn new(profile: Option<&str>) -> Result<Self, ConfigError> {
+        let profile = match profile {
+            Some(profile) => Some(profile.to_string()),
+            None => std::env::var("MLS_PROFILE").ok(),
+        };
         let mut s = Config::new();
-        s.merge(File::with_name("Settings.toml"))?;
+        s.merge(File::with_name("Settings.toml").required(false))?;
+        if let Some(profile) = &profile {
+            s.merge(File::with_name(&format!(
+                "Settings.{}.toml",
+                profile
+            )))?;
+            s.set("profile", profile.as_str())?;
+        }
+        s.merge(Environment::with_prefix("MLS"))?;
         s.try_into()
     }
+
+    /// Whether to join a group we were invited to by `sender` without
+    /// asking.
+    pub fn joins_automatically(&self, sender: &str) -> bool {
+        self.auto_join
+            && (self.auto_join_from.is_empty()
+                || self.auto_join_from.iter().any(|user| user == sender))
+    }
+
+    /// The effective settings, one `key = value` line each.
+    pub fn describe(&self) -> String {
+        let value = serde_json::to_value(self).unwrap();
+        value
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| format!("{} = {}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
@@ -0,0 +1,681 @@
+//! Simulation of several clients sharing an in-memory server, run by
+//! `cargo test`.
+//!
+//! Virtual clients run random sequences of the group operations from
+//! `groups.rs` against a `MemoryTransport`. Blobs don't reach the clients
+//! right away: every blob is delivered to every member after a random
+//! delay, and blobs that become due in the same step are delivered in
+//! random order.
+//! Clients can only fetch blobs that have been delivered to them, so a
+//! client that is behind can't catch up by retrying after a conflict; its
+//! operation is rejected instead, as it would be until the blobs arrive.
+//! After every step, members that have caught up with the server must agree
+//! on the roster and on the number of processed blobs, and nobody may have
+//! detected a fork. At the end, all blobs are delivered, members that are
+//! still behind sync with their view of the server, and the check is
+//! repeated for all members.
+//!
+//! Runs are reproducible: everything that is decided by the simulation
+//! comes from an RNG seeded with `SimulationConfig::seed`. Nothing is
+//! written to disk.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::client::{Blob, Blobs, Invitation, KeyPackage};
+use crate::context::Context;
+use crate::groups::{
+    add_to_group, create_group, do_update, join_group, publish_key_package,
+    remove_from_group, send_message, OperationError,
+};
+use crate::polling::{process_message, sync_group};
+use crate::settings::Settings;
+use crate::state::{RetiredCredential, State};
+use crate::transport::{
+    MemoryTransport, PushEvent, Subscription, Transport, TransportError,
+};
+
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    pub clients: usize,
+    pub steps: usize,
+    pub seed: u64,
+    /// Blobs reach clients up to this many steps after they were sent.
+    pub max_delay: usize,
+    /// Whether blobs that are due in the same step are shuffled.
+    pub reorder: bool,
+    /// How many groups the clients may create.
+    pub max_groups: usize,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            clients: 3,
+            steps: 50,
+            seed: 0,
+            max_delay: 3,
+            reorder: true,
+            max_groups: 2,
+        }
+    }
+}
+
+/// What the simulation expects a group to look like
+#[derive(Default)]
+struct GroupModel {
+    /// Clients that have joined the group.
+    members: BTreeSet<usize>,
+    /// Clients that have been added but haven't joined yet. They are in the
+    /// roster already.
+    invited: BTreeSet<usize>,
+    /// Blobs on the server that have been scheduled for delivery.
+    scheduled: i64,
+}
+
+/// A blob on its way to a client
+struct Delivery {
+    step: usize,
+    client: usize,
+    group_id: String,
+    blob: Blob,
+}
+
+pub struct Simulation {
+    config: SimulationConfig,
+    ctx: Context,
+    rng: StdRng,
+    server: Arc<MemoryTransport>,
+    clients: Vec<Arc<Mutex<State>>>,
+    /// The server as each client sees it.
+    transports: Vec<ClientTransport>,
+    groups: BTreeMap<String, GroupModel>,
+    deliveries: Vec<Delivery>,
+    step: usize,
+    /// Operations that failed because the client was behind.
+    rejected: usize,
+}
+
+impl Simulation {
+    /// Set up the clients. Key files are turned off, so nothing is written
+    /// to the data directory.
+    pub fn new(config: SimulationConfig) -> Result<Simulation, String> {
+        let settings = Settings {
+            key_files: false,
+            ..Settings::default()
+        };
+        let server = Arc::new(MemoryTransport::new());
+        let mut clients = Vec::new();
+        let mut transports = Vec::new();
+        for i in 0..config.clients {
+            let state = State::new(&format!("sim-{}", i));
+            publish_key_package(&state, server.as_ref())?;
+            clients.push(Arc::new(Mutex::new(state)));
+            transports.push(ClientTransport::new(server.clone()));
+        }
+        Ok(Simulation {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            ctx: Context::new(settings),
+            server,
+            clients,
+            transports,
+            groups: BTreeMap::new(),
+            deliveries: Vec::new(),
+            step: 0,
+            rejected: 0,
+        })
+    }
+
+    /// Run all steps and deliver the remaining blobs. Fails at the first
+    /// step that breaks an invariant.
+    pub fn run(&mut self) -> Result<(), String> {
+        info!(target: "repl", "Simulating with seed {}", self.config.seed);
+        for step in 0..self.config.steps {
+            self.step = step;
+            self.random_operation()
+                .map_err(|err| format!("Step {}: {}", step, err))?;
+            self.schedule_blobs()?;
+            self.deliver(false);
+            self.check(false)
+                .map_err(|err| format!("Step {}: {}", step, err))?;
+        }
+        self.deliver(true);
+        self.catch_up()?;
+        self.check(true)
+            .map_err(|err| format!("After the run: {}", err))?;
+        debug!(
+            target: "repl",
+            "{} operations were rejected because the client was behind",
+            self.rejected
+        );
+        Ok(())
+    }
+
+    fn name(&self, client: usize) -> String {
+        self.clients[client].lock().unwrap().name.clone()
+    }
+
+    /// Pick an operation that is possible right now and run it.
+    fn random_operation(&mut self) -> Result<(), String> {
+        let mut candidates: Vec<Operation> = Vec::new();
+        if self.groups.len() < self.config.max_groups {
+            for client in 0..self.clients.len() {
+                candidates.push(Operation::Create(client));
+            }
+        }
+        for (group_id, model) in &self.groups {
+            for &member in &model.members {
+                candidates
+                    .push(Operation::Update(group_id.clone(), member));
+                candidates.push(Operation::Say(group_id.clone(), member));
+                // An add by a member that is behind would be rejected
+                let behind = self.is_behind(member, group_id)?;
+                for client in 0..self.clients.len() {
+                    if !behind
+                        && !model.members.contains(&client)
+                        && !model.invited.contains(&client)
+                    {
+                        candidates.push(Operation::Add(
+                            group_id.clone(),
+                            member,
+                            client,
+                        ));
+                    }
+                }
+                for &other in &model.members {
+                    if other != member {
+                        candidates.push(Operation::Remove(
+                            group_id.clone(),
+                            member,
+                            other,
+                        ));
+                    }
+                }
+            }
+            for &client in &model.invited {
+                candidates.push(Operation::Join(group_id.clone(), client));
+            }
+        }
+        let operation = match candidates.choose(&mut self.rng) {
+            Some(operation) => operation.clone(),
+            None => return Ok(()),
+        };
+        debug!(
+            target: "repl",
+            "Simulation step {}: {:?}",
+            self.step, operation
+        );
+        let behind = match operation.member() {
+            Some((group_id, member)) => self.is_behind(member, group_id)?,
+            None => false,
+        };
+        match self.apply(operation) {
+            Err(OperationError::Conflict) if behind => {
+                debug!(target: "repl", "Rejected: the member is behind");
+                self.rejected += 1;
+                Ok(())
+            }
+            result => result.map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Whether a member hasn't seen all blobs of a group on the server.
+    fn is_behind(
+        &self,
+        member: usize,
+        group_id: &str,
+    ) -> Result<bool, String> {
+        let server_blobs = self
+            .server
+            .get_blobs(group_id, None, None)
+            .map_err(|e| e.to_string())?
+            .blobs
+            .len() as i64;
+        let state = self.clients[member].lock().unwrap();
+        Ok(state.groups.get(group_id).is_some_and(|group_state| {
+            group_state.next_blob < server_blobs
+        }))
+    }
+
+    /// Let members that are behind fetch the blobs they are missing, like
+    /// a round of polling would.
+    fn catch_up(&self) -> Result<(), String> {
+        for (group_id, model) in &self.groups {
+            for &client in &model.members {
+                if !self.is_behind(client, group_id)? {
+                    continue;
+                }
+                let mut state = self.clients[client].lock().unwrap();
+                if let Some(group_state) = state.groups.get_mut(group_id) {
+                    sync_group(
+                        &self.ctx,
+                        &self.transports[client],
+                        group_id,
+                        group_state,
+                    )
+                    .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        operation: Operation,
+    ) -> Result<(), OperationError> {
+        match operation {
+            Operation::Create(client) => {
+                let group_id = format!("group-{}", self.groups.len());
+                create_group(
+                    &mut self.clients[client].lock().unwrap(),
+                    group_id.clone(),
+                )?;
+                let mut model = GroupModel::default();
+                model.members.insert(client);
+                self.groups.insert(group_id, model);
+            }
+            Operation::Add(group_id, member, client) => {
+                let name = self.name(client);
+                add_to_group(
+                    &self.ctx,
+                    &mut self.clients[member].lock().unwrap(),
+                    &self.transports[member],
+                    group_id.clone(),
+                    &name,
+                )?;
+                self.model(&group_id).invited.insert(client);
+            }
+            Operation::Join(group_id, client) => {
+                join_group(
+                    &self.ctx,
+                    &mut self.clients[client].lock().unwrap(),
+                    &self.transports[client],
+                    group_id.clone(),
+                )?;
+                let model = self.model(&group_id);
+                model.invited.remove(&client);
+                model.members.insert(client);
+                // Blobs between the add and now were sent before the client
+                // was a member
+                let next_blob = {
+                    let state = self.clients[client].lock().unwrap();
+                    state.groups[&group_id].next_blob
+                };
+                let scheduled = self.model(&group_id).scheduled;
+                let blobs = self
+                    .server
+                    .get_blobs(&group_id, Some(next_blob), Some(scheduled))?
+                    .blobs;
+                for blob in blobs {
+                    self.deliveries.push(Delivery {
+                        step: self.step,
+                        client,
+                        group_id: group_id.clone(),
+                        blob,
+                    });
+                }
+            }
+            Operation::Update(group_id, member) => {
+                let mut state = self.clients[member].lock().unwrap();
+                do_update(
+                    &self.ctx,
+                    &mut state,
+                    &self.transports[member],
+                    group_id,
+                )?;
+            }
+            Operation::Say(group_id, member) => {
+                let text = format!("message {}", self.step);
+                let mut state = self.clients[member].lock().unwrap();
+                send_message(
+                    &self.ctx,
+                    &mut state,
+                    &self.transports[member],
+                    group_id,
+                    text,
+                )?;
+            }
+            Operation::Remove(group_id, member, client) => {
+                let name = self.name(client);
+                {
+                    let mut state = self.clients[member].lock().unwrap();
+                    remove_from_group(
+                        &self.ctx,
+                        &mut state,
+                        &self.transports[member],
+                        group_id.clone(),
+                        name,
+                    )?;
+                }
+                // The removed client can't follow the group anymore
+                self.clients[client]
+                    .lock()
+                    .unwrap()
+                    .groups
+                    .remove(&group_id);
+                self.model(&group_id).members.remove(&client);
+            }
+        }
+        Ok(())
+    }
+
+    fn model(&mut self, group_id: &str) -> &mut GroupModel {
+        self.groups.get_mut(group_id).unwrap()
+    }
+
+    /// Schedule the blobs that have appeared on the server since the last
+    /// step for delivery to all members.
+    fn schedule_blobs(&mut self) -> Result<(), String> {
+        for (group_id, model) in self.groups.iter_mut() {
+            let blobs = self
+                .server
+                .get_blobs(group_id, Some(model.scheduled), None)
+                .map_err(|e| e.to_string())?
+                .blobs;
+            for blob in blobs {
+                model.scheduled = model.scheduled.max(blob.index + 1);
+                for &client in &model.members {
+                    let delay =
+                        self.rng.gen_range(0, self.config.max_delay + 1);
+                    self.deliveries.push(Delivery {
+                        step: self.step + delay,
+                        client,
+                        group_id: group_id.clone(),
+                        blob: blob.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver the blobs that are due, or all of them if `all` is set.
+    fn deliver(&mut self, all: bool) {
+        let step = self.step;
+        let (mut due, later): (Vec<Delivery>, Vec<Delivery>) = self
+            .deliveries
+            .drain(..)
+            .partition(|delivery| all || delivery.step <= step);
+        self.deliveries = later;
+        if self.config.reorder {
+            due.shuffle(&mut self.rng);
+        } else {
+            due.sort_by_key(|delivery| delivery.blob.index);
+        }
+        for delivery in due {
+            self.transports[delivery.client]
+                .deliver(&delivery.group_id, &delivery.blob);
+            let mut state = self.clients[delivery.client].lock().unwrap();
+            if let Some(group_state) =
+                state.groups.get_mut(&delivery.group_id)
+            {
+                process_message(
+                    &self.ctx,
+                    &delivery.group_id,
+                    group_state,
+                    delivery.blob,
+                );
+            }
+        }
+    }
+
+    /// Check that members that have seen all blobs agree with each other
+    /// and with the model. With `all`, every member has to have seen all
+    /// blobs.
+    fn check(&self, all: bool) -> Result<(), String> {
+        for (group_id, model) in &self.groups {
+            let server_blobs = self
+                .server
+                .get_blobs(group_id, None, None)
+                .map_err(|e| e.to_string())?
+                .blobs
+                .len() as i64;
+            let mut expected_roster: Vec<String> = model
+                .members
+                .union(&model.invited)
+                .map(|&client| self.name(client))
+                .collect();
+            expected_roster.sort();
+            for &client in &model.members {
+                let state = self.clients[client].lock().unwrap();
+                let group_state =
+                    state.groups.get(group_id).ok_or_else(|| {
+                        format!("{} has lost {}", state.name, group_id)
+                    })?;
+                if !group_state.forks.is_empty() {
+                    return Err(format!(
+                        "{} detected a fork in {} at {:?}",
+                        state.name, group_id, group_state.forks
+                    ));
+                }
+                let caught_up = group_state.next_blob == server_blobs
+                    && group_state.pending.is_empty();
+                if !caught_up {
+                    if all || group_state.next_blob > server_blobs {
+                        return Err(format!(
+                            "{} has processed {} of {} blobs in {}",
+                            state.name,
+                            group_state.next_blob,
+                            server_blobs,
+                            group_id
+                        ));
+                    }
+                    continue;
+                }
+                let mut roster: Vec<String> = group_state
+                    .crypto
+                    .get_members()
+                    .iter()
+                    .map(|cred| {
+                        String::from_utf8_lossy(&cred.identity).into()
+                    })
+                    .collect();
+                roster.sort();
+                if roster != expected_roster {
+                    return Err(format!(
+                        "{} sees {:?} in {}, expected {:?}",
+                        state.name, roster, group_id, expected_roster
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Operation {
+    Create(usize),
+    /// Group, member, client to add.
+    Add(String, usize, usize),
+    Join(String, usize),
+    Update(String, usize),
+    Say(String, usize),
+    /// Group, member, client to remove.
+    Remove(String, usize, usize),
+}
+
+impl Operation {
+    /// The group and the member doing the operation, if it's done by a
+    /// member of a group.
+    fn member(&self) -> Option<(&str, usize)> {
+        match self {
+            Operation::Create(_) | Operation::Join(_, _) => None,
+            Operation::Add(group_id, member, _)
+            | Operation::Update(group_id, member)
+            | Operation::Say(group_id, member)
+            | Operation::Remove(group_id, member, _) => {
+                Some((group_id, *member))
+            }
+        }
+    }
+}
+
+/// The server as one client sees it. Blobs can only be fetched once they
+/// have been delivered to the client; everything else goes to the server.
+struct ClientTransport {
+    server: Arc<MemoryTransport>,
+    /// Blobs delivered to the client, by group.
+    delivered: Mutex<HashMap<String, BTreeMap<i64, Blob>>>,
+}
+
+impl ClientTransport {
+    fn new(server: Arc<MemoryTransport>) -> ClientTransport {
+        ClientTransport {
+            server,
+            delivered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn deliver(&self, group_id: &str, blob: &Blob) {
+        self.delivered
+            .lock()
+            .unwrap()
+            .entry(group_id.into())
+            .or_default()
+            .insert(blob.index, blob.clone());
+    }
+}
+
+impl Transport for ClientTransport {
+    fn append_blob(
+        &self,
+        group_id: &str,
+        blob: &Blob,
+    ) -> Result<(), TransportError> {
+        self.server.append_blob(group_id, blob)
+    }
+
+    fn get_blobs(
+        &self,
+        group_id: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Blobs, TransportError> {
+        let delivered = self.delivered.lock().unwrap();
+        let blobs = delivered
+            .get(group_id)
+            .map(|blobs| {
+                blobs
+                    .values()
+                    .filter(|b| from.is_none_or(|from| b.index >= from))
+                    .filter(|b| to.is_none_or(|to| b.index < to))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Blobs { blobs })
+    }
+
+    fn publish_key_package(
+        &self,
+        user: &str,
+        package: &KeyPackage,
+    ) -> Result<(), TransportError> {
+        self.server.publish_key_package(user, package)
+    }
+
+    fn get_key_package(
+        &self,
+        user: &str,
+    ) -> Result<Option<KeyPackage>, TransportError> {
+        self.server.get_key_package(user)
+    }
+
+    fn publish_retired_credentials(
+        &self,
+        user: &str,
+        credentials: &[RetiredCredential],
+    ) -> Result<(), TransportError> {
+        self.server.publish_retired_credentials(user, credentials)
+    }
+
+    fn get_retired_credentials(
+        &self,
+        user: &str,
+    ) -> Result<Vec<RetiredCredential>, TransportError> {
+        self.server.get_retired_credentials(user)
+    }
+
+    fn post_welcome(
+        &self,
+        user: &str,
+        invitation: &Invitation,
+    ) -> Result<(), TransportError> {
+        self.server.post_welcome(user, invitation)
+    }
+
+    fn get_welcomes(
+        &self,
+        user: &str,
+    ) -> Result<Vec<Invitation>, TransportError> {
+        self.server.get_welcomes(user)
+    }
+
+    fn ack_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError> {
+        self.server.ack_welcome(user, id)
+    }
+
+    fn delete_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError> {
+        self.server.delete_welcome(user, id)
+    }
+
+    fn subscribe(
+        &self,
+        user: &str,
+        groups: &[String],
+        events: Sender<PushEvent>,
+    ) -> Result<Subscription, TransportError> {
+        self.server.subscribe(user, groups, events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Simulation, SimulationConfig};
+
+    fn simulate(config: SimulationConfig) {
+        let seed = config.seed;
+        if let Err(err) = Simulation::new(config).unwrap().run() {
+            panic!("Simulation with seed {} failed: {}", seed, err);
+        }
+    }
+
+    #[test]
+    fn in_order_without_delays() {
+        simulate(SimulationConfig {
+            steps: 100,
+            seed: 1,
+            max_delay: 0,
+            reorder: false,
+            ..SimulationConfig::default()
+        });
+    }
+
+    #[test]
+    fn with_delays_and_reordering() {
+        for seed in 0..5 {
+            simulate(SimulationConfig {
+                clients: 4,
+                steps: 100,
+                seed,
+                ..SimulationConfig::default()
+            });
+        }
+    }
+}
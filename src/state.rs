@@ -1,6 +1,10 @@
 use melissa::{group, keys};
-use std::collections::HashMap;
+use ring::digest;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::client::{Blob, KeyPackage};
+use crate::storage::{StateKey, SCHEMA_VERSION};
 use crate::utils::{deserialize_codec, serialize_codec};
 
 /// Group-related state that we track
@@ -19,11 +23,164 @@ pub struct GroupState {
         deserialize_with = "deserialize_codec"
     )]
     pub crypto: group::Group,
+
+    /// Application messages received in the group, oldest first.
+    #[serde(default)]
+    pub inbox: Vec<ReceivedMessage>,
+
+    /// Digests of the last `MAX_DIGESTS` blobs we have processed, by blob
+    /// index. Used to check that the server doesn't rewrite history behind
+    /// our back.
+    #[serde(default)]
+    pub digests: BTreeMap<i64, String>,
+
+    /// The digests of older blobs, folded into one hash.
+    #[serde(default)]
+    pub checkpoint: Option<Checkpoint>,
+
+    /// Indices of blobs where the server's history diverged from what we
+    /// have processed. Once there is a fork, the group is frozen: we don't
+    /// process or send anything in it anymore.
+    #[serde(default)]
+    pub forks: BTreeSet<i64>,
+
+    /// When the poller should do an update for us.
+    #[serde(default)]
+    pub update_policy: UpdatePolicy,
+
+    /// When we last did an update, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub last_update: u64,
+
+    /// Blobs processed since we last did an update.
+    #[serde(default)]
+    pub blobs_since_update: u64,
+
+    /// Blobs that arrived before the blobs preceding them, by blob index.
+    /// They are processed as soon as the gap is filled.
+    #[serde(skip)]
+    pub pending: BTreeMap<i64, Blob>,
+}
+
+impl GroupState {
+    /// Group state for a group where `next_blob` is the first blob we are
+    /// going to process.
+    pub fn new(crypto: group::Group, next_blob: i64) -> Self {
+        GroupState {
+            next_blob,
+            crypto,
+            inbox: Vec::new(),
+            digests: BTreeMap::new(),
+            checkpoint: None,
+            forks: BTreeSet::new(),
+            update_policy: UpdatePolicy::default(),
+            last_update: unix_time(),
+            blobs_since_update: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Remember the digest of a processed blob. The oldest digests are
+    /// folded into the checkpoint, so that the state doesn't grow with the
+    /// history.
+    pub fn record_digest(&mut self, index: i64, digest: String) {
+        self.digests.insert(index, digest);
+        while self.digests.len() > MAX_DIGESTS {
+            let (index, digest) = self.digests.pop_first().unwrap();
+            self.checkpoint
+                .get_or_insert_with(|| Checkpoint::new(index))
+                .extend(&digest);
+        }
+    }
+
+    /// Whether the update policy asks for an update now.
+    pub fn update_due(&self) -> bool {
+        let policy = &self.update_policy;
+        let time_due = policy.every_minutes.is_some_and(|minutes| {
+            unix_time().saturating_sub(self.last_update) >= minutes * 60
+        });
+        let blobs_due = policy
+            .every_blobs
+            .is_some_and(|blobs| self.blobs_since_update >= blobs);
+        time_due || blobs_due
+    }
+
+    /// Remember that we have just done an update.
+    pub fn mark_updated(&mut self) {
+        self.last_update = unix_time();
+        self.blobs_since_update = 0;
+    }
+}
+
+/// How often to do updates in a group automatically. Updates are done by
+/// the poller, when either limit is reached.
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct UpdatePolicy {
+    pub every_minutes: Option<u64>,
+    /// Number of blobs processed since our last update.
+    pub every_blobs: Option<u64>,
+}
+
+/// Seconds since the Unix epoch.
+/// How many blob digests a group keeps before folding them into the
+/// checkpoint.
+pub const MAX_DIGESTS: usize = 1000;
+
+/// A hash chain over the digests of the blobs `from..to`.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    pub from: i64,
+    pub to: i64,
+    pub hash: String,
+}
+
+impl Checkpoint {
+    /// An empty chain that starts at blob `from`.
+    pub fn new(from: i64) -> Checkpoint {
+        Checkpoint {
+            from,
+            to: from,
+            hash: String::new(),
+        }
+    }
+
+    /// Add the digest of blob `to` to the chain.
+    pub fn extend(&mut self, digest: &str) {
+        let input = format!("{}{}", self.hash, digest);
+        self.hash = digest::digest(&digest::SHA256, input.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.to += 1;
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A decrypted application message
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReceivedMessage {
+    /// Index of the blob that carried the message.
+    pub index: i64,
+    /// Who the message claims to be from. Messages aren't signed, so this
+    /// isn't verified.
+    #[serde(alias = "sender")]
+    pub claimed_sender: String,
+    pub text: String,
 }
 
 /// All state that we track
 #[derive(Serialize, Deserialize)]
 pub struct State {
+    /// Schema version, see `storage::SCHEMA_VERSION`.
+    #[serde(default)]
+    pub version: u32,
     pub name: String,
     #[serde(
         serialize_with = "serialize_codec",
@@ -35,26 +192,114 @@ pub struct State {
         deserialize_with = "deserialize_codec"
     )]
     pub credential: keys::BasicCredential,
+    /// Credentials we used before rotating the identity, oldest first.
+    #[serde(default)]
+    pub retired_credentials: Vec<RetiredCredential>,
+    /// The identity `rotate_identity()` is moving to, until every group
+    /// has been moved.
+    #[serde(default)]
+    pub rotation: Option<PendingRotation>,
+    /// The init key for welcome packages. It's used for every group:
+    /// melissa's `Group::new_from_welcome` only takes the identity and
+    /// never the init key's private half, so one-time keys wouldn't
+    /// protect anything.
     #[serde(
         serialize_with = "serialize_codec",
         deserialize_with = "deserialize_codec"
     )]
     pub init_key_bundle: keys::UserInitKeyBundle,
     pub groups: HashMap<String, GroupState>,
+    /// Key for encrypting the state file, if the user has set a passphrase.
+    #[serde(skip)]
+    pub storage_key: Option<StateKey>,
+}
+
+/// A credential that was replaced by `rotate_identity()`. We keep it so that
+/// handshakes signed with it can still be verified.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RetiredCredential {
+    #[serde(
+        serialize_with = "serialize_codec",
+        deserialize_with = "deserialize_codec"
+    )]
+    pub credential: keys::BasicCredential,
+    /// Seconds since the Unix epoch.
+    pub retired_at: u64,
+}
+
+/// An identity that is taking over from the current one. It's saved before
+/// the first group is changed, so that an interrupted rotation can be
+/// finished with the same identity.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingRotation {
+    #[serde(
+        serialize_with = "serialize_codec",
+        deserialize_with = "deserialize_codec"
+    )]
+    pub identity: keys::Identity,
 }
 
 impl State {
     pub fn new(name: &str) -> Self {
         let identity = keys::Identity::random();
         State {
+            version: SCHEMA_VERSION,
             name: name.into(),
             identity: identity.clone(),
-            credential: keys::BasicCredential {
-                identity: name.as_bytes().to_vec(),
-                public_key: identity.public_key,
-            },
+            credential: State::credential_for(name, &identity),
+            retired_credentials: Vec::new(),
+            rotation: None,
             init_key_bundle: keys::UserInitKeyBundle::new(&identity),
             groups: HashMap::new(),
+            storage_key: None,
+        }
+    }
+
+    /// Whether we are in a group. We keep the state of a group we have been
+    /// removed from, but aren't in its roster any more. A group that we
+    /// have just joined counts as well, because we only show up in the
+    /// roster after processing our own add.
+    pub fn is_member(&self, group_id: &str) -> bool {
+        self.groups.get(group_id).is_some_and(|group_state| {
+            let joining = group_state.digests.is_empty()
+                && group_state.checkpoint.is_none();
+            joining
+                || group_state
+                    .crypto
+                    .get_members()
+                    .iter()
+                    .any(|cred| cred.identity == self.name.as_bytes())
+        })
+    }
+
+    /// The credential binding a user name to an identity.
+    pub fn credential_for(
+        name: &str,
+        identity: &keys::Identity,
+    ) -> keys::BasicCredential {
+        keys::BasicCredential {
+            identity: name.as_bytes().to_vec(),
+            public_key: identity.public_key,
+        }
+    }
+
+    /// Switch to a new identity. The old credential is retired, and the
+    /// init key, which belongs to the old identity, is replaced.
+    pub fn replace_identity(&mut self, identity: keys::Identity) {
+        let credential = State::credential_for(&self.name, &identity);
+        self.retired_credentials.push(RetiredCredential {
+            credential: std::mem::replace(&mut self.credential, credential),
+            retired_at: unix_time(),
+        });
+        self.init_key_bundle = keys::UserInitKeyBundle::new(&identity);
+        self.identity = identity;
+    }
+
+    /// The public key material others need to add us to a group.
+    pub fn key_package(&self) -> KeyPackage {
+        KeyPackage {
+            credential: self.credential.clone(),
+            init_key: self.init_key_bundle.init_key.clone(),
         }
     }
 }
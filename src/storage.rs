@@ -0,0 +1,569 @@
+//! Reading and writing the state file.
+//!
+//! The state contains the user's private keys and all group secrets, so it
+//! can be encrypted with a passphrase. An encrypted file looks like this:
+//!
+//! ```text
+//! "MLSSTATE" | version (1 byte) | salt (16 bytes) |
+//! PBKDF2 iterations (4 bytes, big endian) | nonce (12 bytes) |
+//! AES-256-GCM ciphertext of the JSON state
+//! ```
+//!
+//! The header is authenticated as associated data. Files that don't start
+//! with the magic bytes are read as plain JSON, but only if the passphrase
+//! is empty: somebody who can replace the file mustn't be able to slip in
+//! an unencrypted state of their choice.
+//!
+//! The file is replaced atomically: the new state is written to a
+//! temporary file which is then renamed over the old one, and the previous
+//! state is kept in a `.bak` file next to it. If the state file can't be
+//! read, the backup is loaded instead. When the passphrase changes, the
+//! backup is written under the new key as well, so that the old passphrase
+//! (or none) doesn't open any file we keep. All files are only readable by
+//! the user.
+//!
+//! The JSON state carries a schema version. When the layout of `State`
+//! changes, bump `SCHEMA_VERSION` and add a function to `MIGRATIONS` that
+//! converts the JSON of the previous version.
+
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{aead, pbkdf2};
+use std::convert::TryInto;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::state::State;
+
+const MAGIC: &[u8] = b"MLSSTATE";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = 8 + 1 + SALT_LEN + 4;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// Bounds for the iteration count read from a file, so that a tampered
+/// header can neither weaken the key derivation nor make it run forever.
+const MIN_PBKDF2_ITERATIONS: u32 = 10_000;
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// Version of the JSON layout of `State` that we write.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Converts the JSON state of one version into the next one.
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// Migrations of the JSON state, where `MIGRATIONS[n]` converts version `n`
+/// into version `n + 1`. State files written before versioning was
+/// introduced don't have a version and count as version 0.
+const MIGRATIONS: &[Migration] = &[
+    migrate_0_to_1,
+    migrate_1_to_2,
+    migrate_2_to_3,
+    migrate_3_to_4,
+];
+
+/// Version 1 only added the version field; everything else is compatible.
+fn migrate_0_to_1(_: &mut Value) -> Result<(), String> {
+    Ok(())
+}
+
+/// Version 2 replaced the single `init_key_bundle` with a pool of init
+/// keys.
+fn migrate_1_to_2(value: &mut Value) -> Result<(), String> {
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| "The state is not an object".to_string())?;
+    let bundle = object
+        .remove("init_key_bundle")
+        .ok_or_else(|| "The state has no init key".to_string())?;
+    object.insert(
+        "init_keys".into(),
+        serde_json::json!([{ "id": 0, "bundle": bundle }]),
+    );
+    object.insert("next_init_key_id".into(), 1.into());
+    Ok(())
+}
+
+/// Version 3 tracks which init keys have been published. Older clients
+/// published the whole pool every time, so all keys count as published.
+fn migrate_2_to_3(value: &mut Value) -> Result<(), String> {
+    let keys = value
+        .get_mut("init_keys")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| "The state has no init keys".to_string())?;
+    for key in keys {
+        if let Some(key) = key.as_object_mut() {
+            key.insert("published".into(), true.into());
+        }
+    }
+    Ok(())
+}
+
+/// Version 4 went back to a single init key, since melissa doesn't use the
+/// keys in the pool when joining. The oldest key is kept, which is the one
+/// in `<user>.init`.
+fn migrate_3_to_4(value: &mut Value) -> Result<(), String> {
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| "The state is not an object".to_string())?;
+    let bundle = object
+        .remove("init_keys")
+        .and_then(|mut keys| {
+            keys.get_mut(0).map(|key| key["bundle"].take())
+        })
+        .ok_or_else(|| "The state has no init keys".to_string())?;
+    object.remove("next_init_key_id");
+    object.insert("init_key_bundle".into(), bundle);
+    Ok(())
+}
+
+/// Environment variable that can hold the passphrase, for scripted runs.
+pub const PASSPHRASE_VAR: &str = "MLS_STATE_PASSPHRASE";
+
+/// A key for encrypting the state file, derived from a passphrase.
+#[derive(Clone)]
+pub struct StateKey {
+    salt: [u8; SALT_LEN],
+    iterations: u32,
+    key: [u8; 32],
+}
+
+impl StateKey {
+    /// Derive a key from a passphrase with a fresh salt.
+    pub fn new(passphrase: &str) -> Result<StateKey, String> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| "Failed to generate a salt".to_string())?;
+        Ok(StateKey::derive(passphrase, salt, PBKDF2_ITERATIONS))
+    }
+
+    fn derive(
+        passphrase: &str,
+        salt: [u8; SALT_LEN],
+        iterations: u32,
+    ) -> StateKey {
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(iterations).unwrap(),
+            &salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        StateKey {
+            salt,
+            iterations,
+            key,
+        }
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&self.iterations.to_be_bytes());
+        header
+    }
+
+    fn aead_key(&self) -> aead::LessSafeKey {
+        aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_256_GCM, &self.key).unwrap(),
+        )
+    }
+}
+
+/// Ask for a passphrase, unless it's given in the environment. Fails if
+/// there's nobody to ask, rather than going on without encryption; scripts
+/// that want an unencrypted state have to set an empty passphrase.
+pub fn read_passphrase(prompt: &str) -> Result<String, String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    if !atty::is(atty::Stream::Stdin) {
+        return Err(format!(
+            "No terminal to ask for the passphrase, set {} instead",
+            PASSPHRASE_VAR
+        ));
+    }
+    rpassword::read_password_from_tty(Some(prompt))
+        .map_err(|e| e.to_string())
+}
+
+/// Use a new passphrase for the state file. An empty passphrase turns
+/// encryption off.
+pub fn set_passphrase(
+    state: &mut State,
+    passphrase: &str,
+) -> Result<(), String> {
+    state.storage_key = if passphrase.is_empty() {
+        None
+    } else {
+        Some(StateKey::new(passphrase)?)
+    };
+    Ok(())
+}
+
+/// Write state to a file, encrypting it if the state has a key.
+pub fn save_state<P: AsRef<Path>>(
+    state: &State,
+    path: P,
+) -> Result<(), String> {
+    let json = serde_json::to_vec(state).map_err(|e| e.to_string())?;
+    let contents = match &state.storage_key {
+        None => json,
+        Some(key) => {
+            let header = key.header();
+            let mut nonce = [0u8; aead::NONCE_LEN];
+            SystemRandom::new()
+                .fill(&mut nonce)
+                .map_err(|_| "Failed to generate a nonce".to_string())?;
+            let mut ciphertext = json;
+            key.aead_key()
+                .seal_in_place_append_tag(
+                    aead::Nonce::assume_unique_for_key(nonce),
+                    aead::Aad::from(header.as_slice()),
+                    &mut ciphertext,
+                )
+                .map_err(|_| "Failed to encrypt the state".to_string())?;
+            let mut contents = header;
+            contents.extend_from_slice(&nonce);
+            contents.extend_from_slice(&ciphertext);
+            contents
+        }
+    };
+    write_atomically(path.as_ref(), &contents).map_err(|e| e.to_string())
+}
+
+/// Replace the file's contents so that at any moment the file holds either
+/// the old or the new contents, and keep the old contents in a backup.
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    write_private(&tmp_path, contents)?;
+    if path.exists() {
+        let old = fs::read(path)?;
+        let backup = if same_key(&old, contents) {
+            &old[..]
+        } else {
+            contents
+        };
+        write_private(&with_suffix(path, ".bak"), backup)?;
+    }
+    fs::rename(&tmp_path, path)?;
+    // The rename is only durable once the directory entry is on disk
+    sync_dir(path)
+}
+
+/// Whether two state files are encrypted with the same key, or both
+/// unencrypted. Every key has its own salt, so comparing the headers is
+/// enough.
+fn same_key(old: &[u8], new: &[u8]) -> bool {
+    match (old.starts_with(MAGIC), new.starts_with(MAGIC)) {
+        (false, false) => true,
+        (true, true) => old.get(..HEADER_LEN) == new.get(..HEADER_LEN),
+        _ => false,
+    }
+}
+
+/// Write a file that only the user can read and make sure it's on disk.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    restrict_permissions(&file)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn restrict_permissions(file: &fs::File) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+}
+
+/// Other systems have no permission bits to restrict.
+#[cfg(not(unix))]
+fn restrict_permissions(_: &fs::File) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on other systems.
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// `foo.state` -> `foo.state<suffix>`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().into();
+    name.push(suffix);
+    name.into()
+}
+
+/// Read state from a file written by `save_state`, or from its backup if
+/// the file can't be read. `passphrase` is called at most once. An
+/// unencrypted file is only accepted with an empty passphrase.
+pub fn load_state<P, F>(path: P, passphrase: F) -> Result<State, String>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> Result<String, String>,
+{
+    let path = path.as_ref();
+    let mut passphrase = Some(passphrase);
+    let mut cached = None;
+    let mut ask = || {
+        cached
+            .get_or_insert_with(|| (passphrase.take().unwrap())())
+            .clone()
+    };
+    let err = match read_state(path, &mut ask) {
+        Ok(state) => return Ok(state),
+        Err(err) => err,
+    };
+    let backup = with_suffix(path, ".bak");
+    if !backup.exists() {
+        return Err(err);
+    }
+    let state = read_state(&backup, &mut ask).map_err(|_| err.clone())?;
+    warn!(
+        target: "repl",
+        "Couldn't read {} ({}), loaded the backup instead. Changes since \
+         the backup was made are lost.",
+        path.display(),
+        err
+    );
+    Ok(state)
+}
+
+fn read_state(
+    path: &Path,
+    passphrase: &mut dyn FnMut() -> Result<String, String>,
+) -> Result<State, String> {
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    if !contents.starts_with(MAGIC) {
+        if !passphrase()?.is_empty() {
+            return Err("The state file is not encrypted. Give an empty \
+                        passphrase to load it anyway"
+                .into());
+        }
+        return parse_state(&contents);
+    }
+    if contents.len() < HEADER_LEN + aead::NONCE_LEN {
+        return Err("The state file is truncated".into());
+    }
+    let (header, rest) = contents.split_at(HEADER_LEN);
+    if header[MAGIC.len()] != VERSION {
+        return Err(format!(
+            "Unsupported state file version {}",
+            header[MAGIC.len()]
+        ));
+    }
+    let salt_start = MAGIC.len() + 1;
+    let salt: [u8; SALT_LEN] = header[salt_start..salt_start + SALT_LEN]
+        .try_into()
+        .unwrap();
+    let iterations = u32::from_be_bytes(
+        header[salt_start + SALT_LEN..].try_into().unwrap(),
+    );
+    if !(MIN_PBKDF2_ITERATIONS..=MAX_PBKDF2_ITERATIONS)
+        .contains(&iterations)
+    {
+        return Err(format!(
+            "The state file asks for {} key derivation iterations, \
+             expected {} to {}",
+            iterations, MIN_PBKDF2_ITERATIONS, MAX_PBKDF2_ITERATIONS
+        ));
+    }
+    let key = StateKey::derive(&passphrase()?, salt, iterations);
+    let (nonce, ciphertext) = rest.split_at(aead::NONCE_LEN);
+    let mut buffer = ciphertext.to_vec();
+    let json = key
+        .aead_key()
+        .open_in_place(
+            aead::Nonce::try_assume_unique_for_key(nonce).unwrap(),
+            aead::Aad::from(header),
+            &mut buffer,
+        )
+        .map_err(|_| {
+            "Wrong passphrase or corrupted state file".to_string()
+        })?;
+    let mut state = parse_state(json)?;
+    state.storage_key = Some(key);
+    Ok(state)
+}
+
+/// Parse the JSON state, migrating it from older versions if needed.
+fn parse_state(json: &[u8]) -> Result<State, String> {
+    let mut value: Value =
+        serde_json::from_slice(json).map_err(|e| e.to_string())?;
+    let version =
+        value.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "The state file has version {}, but we only support up to {}",
+            version, SCHEMA_VERSION
+        ));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut value)?;
+    }
+    if let Some(object) = value.as_object_mut() {
+        object.insert("version".into(), SCHEMA_VERSION.into());
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+
+    use super::{
+        load_state, save_state, set_passphrase, with_suffix, MAGIC,
+        SCHEMA_VERSION,
+    };
+    use crate::state::State;
+    use crate::testing::TestDir;
+
+    fn passphrase(
+        passphrase: &str,
+    ) -> impl FnOnce() -> Result<String, String> {
+        let passphrase = passphrase.to_string();
+        move || Ok(passphrase)
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let dir = TestDir::new();
+        let path = dir.path.join("alice.state");
+        let mut state = State::new("alice");
+        set_passphrase(&mut state, "secret").unwrap();
+        save_state(&state, &path).unwrap();
+
+        let contents = fs::read(&path).unwrap();
+        assert!(contents.starts_with(MAGIC));
+        assert!(!contents.windows(5).any(|w| w == b"alice"));
+
+        let loaded = load_state(&path, passphrase("secret")).unwrap();
+        assert_eq!(loaded.name, "alice");
+        assert!(loaded.credential == state.credential);
+        assert!(loaded.storage_key.is_some());
+        assert!(load_state(&path, passphrase("wrong")).is_err());
+        assert!(load_state(&path, passphrase("")).is_err());
+    }
+
+    #[test]
+    fn plaintext_needs_an_empty_passphrase() {
+        let dir = TestDir::new();
+        let path = dir.path.join("alice.state");
+        save_state(&State::new("alice"), &path).unwrap();
+        assert!(load_state(&path, passphrase("secret")).is_err());
+        let loaded = load_state(&path, passphrase("")).unwrap();
+        assert!(loaded.storage_key.is_none());
+    }
+
+    #[test]
+    fn the_backup_follows_the_passphrase() {
+        let dir = TestDir::new();
+        let path = dir.path.join("alice.state");
+        let backup = with_suffix(&path, ".bak");
+        let mut state = State::new("alice");
+        save_state(&state, &path).unwrap();
+        save_state(&state, &path).unwrap();
+        assert!(fs::read(&backup)
+            .unwrap()
+            .windows(5)
+            .any(|w| w == b"alice"));
+
+        set_passphrase(&mut state, "secret").unwrap();
+        save_state(&state, &path).unwrap();
+        let contents = fs::read(&backup).unwrap();
+        assert!(contents.starts_with(MAGIC));
+        fs::write(&path, b"garbage").unwrap();
+        assert!(load_state(&path, passphrase("")).is_err());
+        assert!(load_state(&path, passphrase("secret")).is_ok());
+
+        set_passphrase(&mut state, "other").unwrap();
+        save_state(&state, &path).unwrap();
+        fs::write(&path, b"garbage").unwrap();
+        assert!(load_state(&path, passphrase("secret")).is_err());
+        assert!(load_state(&path, passphrase("other")).is_ok());
+    }
+
+    #[test]
+    fn fall_back_to_the_backup() {
+        let dir = TestDir::new();
+        let path = dir.path.join("alice.state");
+        let mut state = State::new("alice");
+        set_passphrase(&mut state, "secret").unwrap();
+        save_state(&state, &path).unwrap();
+        save_state(&state, &path).unwrap();
+        fs::write(&path, b"garbage").unwrap();
+        let loaded = load_state(&path, passphrase("secret")).unwrap();
+        assert_eq!(loaded.name, "alice");
+    }
+
+    #[test]
+    fn read_older_versions() {
+        let dir = TestDir::new();
+        let path = dir.path.join("alice.state");
+        save_state(&State::new("alice"), &path).unwrap();
+        let current: Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let bundle = current["init_key_bundle"].clone();
+
+        // Before versioning, the layout had a single init key as well
+        let mut unversioned = current.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
+        // Version 3 had a pool of init keys
+        let mut pool = current;
+        let object = pool.as_object_mut().unwrap();
+        object.remove("init_key_bundle");
+        object.insert(
+            "init_keys".into(),
+            json!([
+                { "id": 4, "bundle": bundle, "published": true },
+                { "id": 5, "bundle": "", "published": false },
+            ]),
+        );
+        object.insert("next_init_key_id".into(), 6.into());
+        object.insert("version".into(), 3.into());
+
+        for value in &[unversioned, pool] {
+            fs::write(&path, serde_json::to_vec(value).unwrap()).unwrap();
+            let loaded = load_state(&path, passphrase("")).unwrap();
+            assert_eq!(loaded.version, SCHEMA_VERSION);
+            let saved = serde_json::to_value(&loaded).unwrap();
+            assert_eq!(saved["init_key_bundle"], bundle);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_user_can_read_the_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new();
+        let path = dir.path.join("alice.state");
+        let state = State::new("alice");
+        save_state(&state, &path).unwrap();
+        save_state(&state, &path).unwrap();
+        for path in &[path.clone(), with_suffix(&path, ".bak")] {
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
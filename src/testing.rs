@@ -0,0 +1,91 @@
+//! Helpers for the tests.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::api::MlsClient;
+use crate::settings::Settings;
+use crate::transport::{MemoryTransport, Transport};
+
+/// A data directory of its own for a test, removed afterwards.
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "mls-client-test-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    /// Settings that keep everything in this directory.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            data_dir: self.path.to_string_lossy().into(),
+            ..Settings::default()
+        }
+    }
+
+    /// Create a user with their state in this directory.
+    pub fn client(
+        &self,
+        name: &str,
+        transport: &Arc<dyn Transport>,
+    ) -> MlsClient {
+        MlsClient::create(name, self.settings(), transport.clone()).unwrap()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An in-memory server for a test.
+pub fn memory_transport() -> Arc<dyn Transport> {
+    Arc::new(MemoryTransport::new())
+}
+
+/// The texts of the messages a client has received in a group.
+pub fn texts(client: &MlsClient, group_id: &str) -> Vec<String> {
+    let messages = client.messages(group_id).unwrap();
+    messages.into_iter().map(|m| m.text).collect()
+}
+
+/// Alice and Bob, who are both members of group `g`, and the server they
+/// share.
+pub struct TwoMembers {
+    pub transport: Arc<dyn Transport>,
+    pub alice: MlsClient,
+    pub bob: MlsClient,
+    pub dir: TestDir,
+}
+
+/// Alice creates `g` and adds Bob, who joins through his mailbox and has
+/// processed his add.
+pub fn two_members_in_a_group() -> TwoMembers {
+    let dir = TestDir::new();
+    let transport = memory_transport();
+    let alice = dir.client("alice", &transport);
+    let bob = dir.client("bob", &transport);
+    alice.create_group("g").unwrap();
+    alice.add("g", "bob").unwrap();
+    bob.join("g").unwrap();
+    bob.sync().unwrap();
+    TwoMembers {
+        transport,
+        alice,
+        bob,
+        dir,
+    }
+}
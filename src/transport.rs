@@ -0,0 +1,389 @@
+//! Abstraction over the server that stores group blobs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::client::{Blob, Blobs, Invitation, KeyPackage};
+use crate::state::RetiredCredential;
+
+/// Errors that can happen while talking to the server.
+#[derive(Debug)]
+pub enum TransportError {
+    /// The HTTP request failed or the server returned an error status.
+    Http(reqwest::Error),
+    /// The blob index is already taken by another blob.
+    Conflict,
+    /// Any other failure.
+    Other(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Http(err) => write!(f, "{}", err),
+            TransportError::Conflict => {
+                write!(f, "The blob index is already taken")
+            }
+            TransportError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(err: reqwest::Error) -> Self {
+        TransportError::Http(err)
+    }
+}
+
+/// A notification pushed by the server.
+#[derive(Clone, Debug)]
+pub enum PushEvent {
+    /// A blob was stored in one of the user's groups.
+    Blob {
+        user: String,
+        group_id: String,
+        index: i64,
+    },
+    /// An invitation arrived in the user's mailbox.
+    Welcome { user: String },
+    /// The connection for the user was lost; nothing more will be pushed.
+    Disconnected { user: String },
+}
+
+/// An open push subscription. Dropping it closes the subscription; a
+/// transport that reads events on a thread has to make sure that the thread
+/// notices that and ends, also when no events arrive.
+pub struct Subscription {
+    closed: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    /// A subscription that ends when `closed` is set.
+    pub fn new(closed: Arc<AtomicBool>) -> Subscription {
+        Subscription { closed }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Everything the client needs from the server.
+pub trait Transport: Send + Sync {
+    /// Store a blob for a specific group.
+    fn append_blob(
+        &self,
+        group_id: &str,
+        blob: &Blob,
+    ) -> Result<(), TransportError>;
+
+    /// Receive blobs for a specific group, starting with `from` (inclusive)
+    /// and ending before `to`.
+    fn get_blobs(
+        &self,
+        group_id: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Blobs, TransportError>;
+
+    /// Publish the user's key package, replacing the previous one.
+    fn publish_key_package(
+        &self,
+        user: &str,
+        package: &KeyPackage,
+    ) -> Result<(), TransportError>;
+
+    /// Fetch a user's key package, if they have published one.
+    fn get_key_package(
+        &self,
+        user: &str,
+    ) -> Result<Option<KeyPackage>, TransportError>;
+
+    /// Publish the credentials a user has retired with
+    /// `rotate_identity()`, replacing the previous list, so that others can
+    /// still verify what was signed with them.
+    fn publish_retired_credentials(
+        &self,
+        user: &str,
+        credentials: &[RetiredCredential],
+    ) -> Result<(), TransportError>;
+
+    /// Fetch the credentials a user has retired, oldest first.
+    fn get_retired_credentials(
+        &self,
+        user: &str,
+    ) -> Result<Vec<RetiredCredential>, TransportError>;
+
+    /// Put an invitation into a user's mailbox. The server assigns the
+    /// invitation's `id`.
+    fn post_welcome(
+        &self,
+        user: &str,
+        invitation: &Invitation,
+    ) -> Result<(), TransportError>;
+
+    /// Fetch all invitations from a user's mailbox.
+    fn get_welcomes(
+        &self,
+        user: &str,
+    ) -> Result<Vec<Invitation>, TransportError>;
+
+    /// Mark an invitation as seen by the recipient. It stays in the
+    /// mailbox until it's deleted.
+    fn ack_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError>;
+
+    /// Remove an invitation from a user's mailbox, after it has been
+    /// accepted or declined.
+    fn delete_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError>;
+
+    /// Get notified about new blobs in `groups` and new invitations for
+    /// `user`. Events are sent to `events` until the subscription is
+    /// dropped; when the connection is lost, `PushEvent::Disconnected` is
+    /// sent.
+    fn subscribe(
+        &self,
+        user: &str,
+        groups: &[String],
+        events: Sender<PushEvent>,
+    ) -> Result<Subscription, TransportError>;
+}
+
+/// An in-process server, useful for tests and for simulating several
+/// clients without running the real server.
+#[derive(Default)]
+pub struct MemoryTransport {
+    groups: Mutex<HashMap<String, Vec<Blob>>>,
+    key_packages: Mutex<HashMap<String, KeyPackage>>,
+    retired_credentials: Mutex<HashMap<String, Vec<RetiredCredential>>>,
+    welcomes: Mutex<HashMap<String, Vec<Invitation>>>,
+    next_welcome_id: Mutex<u64>,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+/// A push subscription to a `MemoryTransport`
+struct Subscriber {
+    user: String,
+    groups: Vec<String>,
+    events: Sender<PushEvent>,
+    closed: Arc<AtomicBool>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Send events to subscribers, and forget the subscribers that are
+    /// gone. `event` decides what a subscriber gets, if anything.
+    fn notify<F>(&self, event: F)
+    where
+        F: Fn(&Subscriber) -> Option<PushEvent>,
+    {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            if subscriber.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+            match event(subscriber) {
+                Some(event) => subscriber.events.send(event).is_ok(),
+                None => true,
+            }
+        });
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn append_blob(
+        &self,
+        group_id: &str,
+        blob: &Blob,
+    ) -> Result<(), TransportError> {
+        let mut groups = self.groups.lock().unwrap();
+        let blobs = groups.entry(group_id.into()).or_default();
+        // Like the real server, only accept the blob that comes right
+        // after the last stored one
+        if blob.index < blobs.len() as i64 {
+            return Err(TransportError::Conflict);
+        }
+        if blob.index > blobs.len() as i64 {
+            return Err(TransportError::Other(format!(
+                "Expected blob index {}, got {}",
+                blobs.len(),
+                blob.index
+            )));
+        }
+        blobs.push(blob.clone());
+        drop(groups);
+        self.notify(|subscriber| {
+            if subscriber.groups.iter().any(|g| g == group_id) {
+                Some(PushEvent::Blob {
+                    user: subscriber.user.clone(),
+                    group_id: group_id.into(),
+                    index: blob.index,
+                })
+            } else {
+                None
+            }
+        });
+        Ok(())
+    }
+
+    fn get_blobs(
+        &self,
+        group_id: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Blobs, TransportError> {
+        let groups = self.groups.lock().unwrap();
+        let blobs = groups
+            .get(group_id)
+            .map(|blobs| {
+                blobs
+                    .iter()
+                    .filter(|b| from.is_none_or(|from| b.index >= from))
+                    .filter(|b| to.is_none_or(|to| b.index < to))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Blobs { blobs })
+    }
+
+    fn publish_key_package(
+        &self,
+        user: &str,
+        package: &KeyPackage,
+    ) -> Result<(), TransportError> {
+        self.key_packages
+            .lock()
+            .unwrap()
+            .insert(user.into(), package.clone());
+        Ok(())
+    }
+
+    fn get_key_package(
+        &self,
+        user: &str,
+    ) -> Result<Option<KeyPackage>, TransportError> {
+        Ok(self.key_packages.lock().unwrap().get(user).cloned())
+    }
+
+    fn publish_retired_credentials(
+        &self,
+        user: &str,
+        credentials: &[RetiredCredential],
+    ) -> Result<(), TransportError> {
+        self.retired_credentials
+            .lock()
+            .unwrap()
+            .insert(user.into(), credentials.to_vec());
+        Ok(())
+    }
+
+    fn get_retired_credentials(
+        &self,
+        user: &str,
+    ) -> Result<Vec<RetiredCredential>, TransportError> {
+        Ok(self
+            .retired_credentials
+            .lock()
+            .unwrap()
+            .get(user)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn post_welcome(
+        &self,
+        user: &str,
+        invitation: &Invitation,
+    ) -> Result<(), TransportError> {
+        let mut next_id = self.next_welcome_id.lock().unwrap();
+        *next_id += 1;
+        let mut invitation = invitation.clone();
+        invitation.id = *next_id;
+        invitation.acknowledged = false;
+        self.welcomes
+            .lock()
+            .unwrap()
+            .entry(user.into())
+            .or_default()
+            .push(invitation);
+        self.notify(|subscriber| {
+            if subscriber.user == user {
+                Some(PushEvent::Welcome { user: user.into() })
+            } else {
+                None
+            }
+        });
+        Ok(())
+    }
+
+    fn get_welcomes(
+        &self,
+        user: &str,
+    ) -> Result<Vec<Invitation>, TransportError> {
+        Ok(self
+            .welcomes
+            .lock()
+            .unwrap()
+            .get(user)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn ack_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError> {
+        let mut welcomes = self.welcomes.lock().unwrap();
+        let invitation = welcomes
+            .get_mut(user)
+            .and_then(|mailbox| mailbox.iter_mut().find(|i| i.id == id))
+            .ok_or_else(|| {
+                TransportError::Other("No such invitation".into())
+            })?;
+        invitation.acknowledged = true;
+        Ok(())
+    }
+
+    fn delete_welcome(
+        &self,
+        user: &str,
+        id: u64,
+    ) -> Result<(), TransportError> {
+        if let Some(mailbox) = self.welcomes.lock().unwrap().get_mut(user) {
+            mailbox.retain(|i| i.id != id);
+        }
+        Ok(())
+    }
+
+    fn subscribe(
+        &self,
+        user: &str,
+        groups: &[String],
+        events: Sender<PushEvent>,
+    ) -> Result<Subscription, TransportError> {
+        let closed = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            user: user.into(),
+            groups: groups.to_vec(),
+            events,
+            closed: closed.clone(),
+        });
+        Ok(Subscription::new(closed))
+    }
+}
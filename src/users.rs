@@ -0,0 +1,73 @@
+//! Several local users in one client process.
+//!
+//! The REPL works on behalf of the current user; `switch(name)` makes a
+//! different user current, so one script can drive all parties of a
+//! scenario.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::state::State;
+
+/// All users known to this process, by name
+pub struct Users {
+    users: BTreeMap<String, Arc<Mutex<State>>>,
+    current: String,
+}
+
+impl Users {
+    pub fn new(state: State) -> Users {
+        let mut users = Users {
+            users: BTreeMap::new(),
+            current: state.name.clone(),
+        };
+        users.insert(state);
+        users
+    }
+
+    /// The user commands are run for.
+    pub fn current(&self) -> Arc<Mutex<State>> {
+        self.users[&self.current].clone()
+    }
+
+    pub fn current_name(&self) -> &str {
+        &self.current
+    }
+
+    /// Add a user and make them current. A user with the same name is
+    /// replaced.
+    pub fn insert(&mut self, state: State) {
+        self.current = state.name.clone();
+        self.users
+            .insert(state.name.clone(), Arc::new(Mutex::new(state)));
+    }
+
+    /// Make another user current.
+    pub fn switch(&mut self, name: &str) -> Result<(), String> {
+        if self.users.contains_key(name) {
+            self.current = name.into();
+            Ok(())
+        } else {
+            Err(format!("Unknown user {}!", name))
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.users.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.users.contains_key(name)
+    }
+
+    /// States of all users, e.g. for polling.
+    pub fn all(&self) -> Vec<Arc<Mutex<State>>> {
+        self.users.values().cloned().collect()
+    }
+}
+
+/// The current user's state. The lock on `users` is released before
+/// returning, so the state can be locked without holding it.
+pub fn current(users: &Arc<Mutex<Users>>) -> Arc<Mutex<State>> {
+    users.lock().unwrap().current()
+}
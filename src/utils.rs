@@ -7,9 +7,8 @@ use std::path::Path;
 
 /// Read a value from a file using `Codec`.
 pub fn read_codec<P: AsRef<Path>, T: Codec>(path: P) -> io::Result<T> {
-    Codec::decode_detached(fs::read(path)?.as_ref()).map_err(|e| {
-        io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
-    })
+    Codec::decode_detached(fs::read(path)?.as_ref())
+        .map_err(|e| io::Error::other(format!("{:?}", e)))
 }
 
 /// Write a value into a file using `Codec`.